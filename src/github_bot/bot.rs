@@ -1,7 +1,10 @@
 use dioxus::html::g;
 use octocrab::models::{App, AppId};
 
-use crate::server::server::PullRequestInfo;
+use crate::server::{
+    repo_config::{ConfigError, MergeMethod, RepoConfig, CONFIG_PATH},
+    server::PullRequestInfo,
+};
 
 fn read_env_var(var_name: &str) -> String {
     let err = format!("Missing environment variable: {var_name}");
@@ -48,123 +51,224 @@ pub async fn get_install_specific_octo(
     Some(crab)
 }
 
-pub async fn merge(potential_merge: PullRequestInfo) {
+/// A client for the installation of the app on a repo, or why there is none
+async fn installed_octocrab(
+    repo_owner: &str,
+    repo_name: &str,
+) -> Result<octocrab::Octocrab, String> {
+    let Some(octocrab) = get_octocrab().await else {
+        println!("Error: Octocrab failed to build.");
+        return Err("Octocrab failed to build".to_string());
+    };
+    get_install_specific_octo(&octocrab, repo_owner, repo_name)
+        .await
+        .ok_or_else(|| format!("Git Lucky isn't installed on {repo_owner}/{repo_name}"))
+}
+
+/// The message of a failed GitHub call
+fn github_error(e: octocrab::Error) -> String {
+    match e {
+        octocrab::Error::GitHub { source, .. } => {
+            println!("GitHub error: {:?}", source);
+            source.message
+        }
+        e => {
+            println!("Error: {:?}", e);
+            e.to_string()
+        }
+    }
+}
+
+/// Merge the PR, then comment `comment` on it along with why it couldn't be merged if it wasn't. Returns the SHA of
+/// the merge commit, or why it couldn't be merged
+pub async fn merge(potential_merge: PullRequestInfo, comment: String) -> Result<String, String> {
+    let people_accepted = potential_merge.right_votes();
+    let people_denied = potential_merge.left_votes();
     let PullRequestInfo {
         pull_request,
-        left_votes,
-        right_votes,
-        creation_time,
+        config,
+        ..
     } = potential_merge;
 
-    let token = pull_request.key.clone();
-    let people_accepted = right_votes;
-    let people_denied = left_votes;
     let branch_to_merge = pull_request.branch_to_merge.clone();
     let branch_to_merge_into = pull_request.branch_to_merge_into.clone();
     let repo_owner = pull_request.repo_owner.clone();
     let repo_name = pull_request.repo_name.clone();
-    let maybe_octo = get_octocrab().await;
     let pr_number = pull_request.pr_number;
 
-    if let Some(octocrab) = maybe_octo {
-        let crab = get_install_specific_octo(&octocrab, &repo_owner, &repo_name).await;
-
-        if let Some(crab) = crab {
-            let comment = if people_accepted == 0 {
-                "No one voted, so this PR is automatically accepted!".to_string()
-            } else {
-                format!(
-                    "The people have spoken and have accepted this PR! {} accepted, {} denied.",
-                    people_accepted, people_denied
-                )
-            };
+    let crab = installed_octocrab(&repo_owner, &repo_name).await?;
+    let Some(head_sha) = pull_request.key.clone() else {
+        return Err("The PR has no head SHA to merge".to_string());
+    };
 
-            let _ = crab
-                .issues(&repo_owner, &repo_name)
-                .create_comment(pr_number, comment)
-                .await
-                .unwrap();
-
-            println!(
-                "Repo owner: {}, repo name: {}, branch to merge: {}, branch to merge into: {}",
-                repo_owner, repo_name, branch_to_merge, branch_to_merge_into
-            );
-
-            let result = crab
-                .repos(&repo_owner, &repo_name)
-                .merge(&token.unwrap(), branch_to_merge_into)
-                .commit_message(format!(
-                    "The people have merged {}, {} accepted, {} denied.",
-                    branch_to_merge, people_accepted, people_denied
-                ))
-                .send()
-                .await;
-
-            if let Err(e) = result {
-                match e {
-                    octocrab::Error::GitHub { source, backtrace } => {
-                        if source.message.contains("Merge conflict") {
-                            let comment = "The people wanted to merge this PR, but there was a merge conflict. Please resolve the conflict and try again.".to_string();
-                            let _ = crab
-                                .issues(&repo_owner, &repo_name)
-                                .create_comment(pr_number, comment)
-                                .await
-                                .unwrap();
-                        } else {
-                            println!("GitHub error: {:?}", source);
-                        }
-                    }
-                    _ => println!("Error: {:?}", e),
-                }
-            }
+    println!(
+        "Repo owner: {}, repo name: {}, branch to merge: {}, branch to merge into: {}",
+        repo_owner, repo_name, branch_to_merge, branch_to_merge_into
+    );
+
+    let method = match config.merge_method {
+        MergeMethod::Merge => octocrab::params::pulls::MergeMethod::Merge,
+        MergeMethod::Squash => octocrab::params::pulls::MergeMethod::Squash,
+        MergeMethod::Rebase => octocrab::params::pulls::MergeMethod::Rebase,
+    };
+    // Pinning the head SHA makes GitHub refuse the merge if commits were pushed after the vote
+    let result = crab
+        .pulls(&repo_owner, &repo_name)
+        .merge(pr_number)
+        .sha(head_sha)
+        .method(method)
+        .message(format!(
+            "The people have merged {}, {} accepted, {} denied.",
+            branch_to_merge, people_accepted, people_denied
+        ))
+        .send()
+        .await;
+
+    let merged = match result {
+        Ok(merge) if merge.merged => Ok(merge.sha.unwrap_or_default()),
+        Ok(merge) => Err(merge
+            .message
+            .unwrap_or_else(|| "GitHub didn't merge the PR".to_string())),
+        Err(e) => Err(github_error(e)),
+    };
+
+    // Only tell the PR it was merged once it really was
+    let comment = match &merged {
+        Ok(_) => comment,
+        Err(error) if error.contains("Merge conflict") || error.contains("not mergeable") => format!(
+            "{comment}\n\nThe people wanted to merge this PR, but there was a merge conflict. Please resolve the conflict and try again."
+        ),
+        Err(error) => {
+            format!("{comment}\n\nThe people wanted to merge this PR, but GitHub refused: {error}")
         }
-    } else {
-        println!("Error: Octocrab failed to build.");
-    }
+    };
+    post_comment(&crab, &repo_owner, &repo_name, pr_number, comment).await;
+    merged
 }
 
-pub async fn deny_merge(potential_merge: PullRequestInfo) {
-    let mut should_return = false;
+/// Close the PR, then comment `comment` on it along with why it couldn't be closed if it wasn't. Returns why it
+/// couldn't be closed
+pub async fn deny_merge(potential_merge: PullRequestInfo, comment: String) -> Result<(), String> {
     let pr_number = potential_merge.pull_request.pr_number;
-    let token = potential_merge.pull_request.key.clone();
-    let people_accepted = potential_merge.right_votes;
-    let people_denied = potential_merge.left_votes;
-    let repo_owner = potential_merge.pull_request.repo_owner.clone();
-    let repo_name = potential_merge.pull_request.repo_name.clone();
-
-    let octocrab = get_octocrab().await.unwrap();
-    let crab = get_install_specific_octo(&octocrab, &repo_owner, &repo_name).await;
-
-    let comment = if people_denied == 0 {
-        "No one voted, so this PR is automatically denied!".to_string()
-    } else {
-        format!(
-            "The people have spoken and have denied this PR! {} accepted, {} denied.",
-            people_accepted, people_denied
-        )
-    };
+    let repo_owner = potential_merge.pull_request.repo_owner;
+    let repo_name = potential_merge.pull_request.repo_name;
+    let crab = installed_octocrab(&repo_owner, &repo_name).await?;
 
     println!(
         "potential merge: {:?}",
         potential_merge.pull_request.branch_to_merge_into
     );
 
-    // Comment on the PR
-    let _ = crab
-        .as_ref()
-        .unwrap()
-        .issues(&repo_owner, &repo_name)
-        .create_comment(pr_number, comment)
-        .await
-        .unwrap();
-
-    let _ = crab
-        .as_ref()
-        .unwrap()
-        .pulls(repo_owner, repo_name)
+    let closed = crab
+        .pulls(&repo_owner, &repo_name)
         .update(pr_number)
         .state(octocrab::params::pulls::State::Closed)
         .send()
         .await
-        .unwrap();
+        .map(|_| ())
+        .map_err(github_error);
+
+    // Only tell the PR it was closed once it really was
+    let comment = match &closed {
+        Ok(()) => comment,
+        Err(error) => {
+            format!("{comment}\n\nThe people wanted to close this PR, but GitHub refused: {error}")
+        }
+    };
+    post_comment(&crab, &repo_owner, &repo_name, pr_number, comment).await;
+    closed
+}
+
+/// Load `.gitlucky.toml` from the default branch of a repo. Repos without one get the default config
+///
+/// Failed fetches are retried a couple of times before giving up, so a hiccup on GitHub's side doesn't cost the PR
+/// its vote
+pub async fn load_repo_config(
+    repo_owner: &str,
+    repo_name: &str,
+) -> Result<RepoConfig, ConfigError> {
+    const ATTEMPTS: u64 = 3;
+
+    let mut attempt = 1;
+    loop {
+        match fetch_repo_config(repo_owner, repo_name).await {
+            Err(ConfigError::Unavailable(e)) if attempt < ATTEMPTS => {
+                println!(
+                    "Failed to fetch the config of {}/{}, retrying: {}",
+                    repo_owner, repo_name, e
+                );
+                tokio::time::sleep(std::time::Duration::from_secs(attempt)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+async fn fetch_repo_config(repo_owner: &str, repo_name: &str) -> Result<RepoConfig, ConfigError> {
+    let Some(octocrab) = get_octocrab().await else {
+        return Err(ConfigError::Unavailable(
+            "couldn't authenticate as the GitHub App".to_string(),
+        ));
+    };
+    let Some(crab) = get_install_specific_octo(&octocrab, repo_owner, repo_name).await else {
+        return Err(ConfigError::Unavailable(format!(
+            "couldn't get an installation token for {repo_owner}/{repo_name}"
+        )));
+    };
+
+    let content = crab
+        .repos(repo_owner, repo_name)
+        .get_content()
+        .path(CONFIG_PATH)
+        .send()
+        .await;
+    match content {
+        Ok(mut content) => {
+            let Some(source) = content
+                .take_items()
+                .into_iter()
+                .next()
+                .and_then(|file| file.decoded_content())
+            else {
+                return Err(ConfigError::Invalid(vec![format!(
+                    "`{CONFIG_PATH}` is not a file"
+                )]));
+            };
+            RepoConfig::parse(&source).map_err(ConfigError::Invalid)
+        }
+        Err(octocrab::Error::GitHub { source, .. }) if source.status_code == 404 => {
+            Ok(RepoConfig::default())
+        }
+        Err(e) => Err(ConfigError::Unavailable(format!(
+            "failed to read `{CONFIG_PATH}`: {e}"
+        ))),
+    }
+}
+
+/// Leave a comment on a PR through a client that is already installed on the repo. Failing to comment
+/// doesn't change what happened to the PR, so it is only logged
+async fn post_comment(
+    crab: &octocrab::Octocrab,
+    repo_owner: &str,
+    repo_name: &str,
+    pr_number: u64,
+    comment: String,
+) {
+    if let Err(e) = crab
+        .issues(repo_owner, repo_name)
+        .create_comment(pr_number, comment)
+        .await
+    {
+        println!("Failed to comment on #{}: {:?}", pr_number, e);
+    }
+}
+
+/// Leave a comment on a PR
+pub async fn comment(repo_owner: &str, repo_name: &str, pr_number: u64, comment: String) {
+    let octocrab = get_octocrab().await.unwrap();
+    let crab = get_install_specific_octo(&octocrab, repo_owner, repo_name).await;
+    if let Some(crab) = crab {
+        post_comment(&crab, repo_owner, repo_name, pr_number, comment).await;
+    }
 }
@@ -4,14 +4,14 @@ use serde::{Deserialize, Serialize};
 #[cfg(feature = "server")]
 use crate::server::server::Server;
 #[cfg(not(feature = "server"))]
-use views::Home;
+use views::{History, Home, Leaderboard};
 
 #[cfg(feature = "server")]
 mod github_bot;
 mod server;
 mod views;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PullRequest {
     pub diff_url: String,
     pub diff: String,
@@ -29,10 +29,241 @@ pub struct PullRequest {
     pub profile_pic_url: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl PullRequest {
+    pub fn id(&self) -> PrId {
+        PrId {
+            owner: self.repo_owner.clone(),
+            repo: self.repo_name.clone(),
+            number: self.pr_number,
+            revision: self.key.clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// Identifies a PR at a specific revision, so a vote always lands on the exact card the voter saw
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PrId {
+    pub owner: String,
+    pub repo: String,
+    pub number: u64,
+    /// The head SHA of the PR
+    pub revision: String,
+}
+
+impl PrId {
+    /// Identifies the PR regardless of its revision. The queue is keyed by this
+    pub fn key(&self) -> String {
+        format!("{}/{}#{}", self.owner, self.repo, self.number)
+    }
+}
+
+impl std::fmt::Display for PrId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let short = self.revision.get(..7).unwrap_or(&self.revision);
+        write!(f, "{}@{}", self.key(), short)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Direction {
     Left,
     Right,
+    /// Seen, but abstained. Doesn't count toward the outcome
+    Skip,
+}
+
+/// An error from the API. Serialized as `{"code": ..., "message": ...}`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApiError {
+    pub code: ApiErrorCode,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiErrorCode {
+    /// The request needs a voter, and it didn't come from one
+    Unauthorized,
+    NotFound,
+    /// The vote on the PR is already over
+    VoteClosed,
+    /// New commits were pushed to the PR since the voter saw it
+    StaleRevision,
+    /// The body of the request couldn't be read
+    InvalidRequest,
+    RateLimited,
+    /// The server is already serving as many of these requests as it takes
+    Busy,
+    /// Something went wrong on the server, like the store failing to save a change
+    Internal,
+}
+
+impl ApiError {
+    pub fn new(code: ApiErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// A voter signed in with GitHub
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct User {
+    pub id: u64,
+    pub login: String,
+}
+
+/// A queued PR as listed by `/prs`. Leaves out the diff and anything secret
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueueEntry {
+    pub id: PrId,
+    pub title: String,
+    pub repo_owner: String,
+    pub repo_name: String,
+    pub author: String,
+    pub creation_time: chrono::DateTime<chrono::Utc>,
+    pub deadline: chrono::DateTime<chrono::Utc>,
+    /// Votes that count toward the outcome
+    pub votes: usize,
+    /// Only included when asked for with `tallies=true`
+    pub tally: Option<QueueTally>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct QueueTally {
+    pub accepted: usize,
+    pub denied: usize,
+    pub abstained: usize,
+}
+
+/// A page of `/prs`. Pass `next_cursor` as `cursor` to get the next one
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueuePage {
+    pub items: Vec<QueueEntry>,
+    /// None on the last page
+    pub next_cursor: Option<String>,
+}
+
+/// How the vote on a PR ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Merged,
+    /// The PR was accepted, but GitHub refused to merge it
+    MergeFailed,
+    Closed,
+    /// The PR was left for the maintainers
+    LeftOpen,
+}
+
+/// The archived result of a finalized vote, as listed by `/history`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryRecord {
+    pub id: PrId,
+    pub title: String,
+    pub author: String,
+    pub outcome: Outcome,
+    pub tally: QueueTally,
+    /// The decision policy of the repo when the vote ended
+    pub policy: String,
+    /// What the bot commented on the PR
+    pub comment: String,
+    pub merge_sha: Option<String>,
+    /// Why merging failed, for [`Outcome::MergeFailed`], or why a denied PR couldn't be closed
+    pub error: Option<String>,
+    pub finalized_time: chrono::DateTime<chrono::Utc>,
+    /// The draw that decided the PR, for repos with the `lottery` policy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lottery: Option<LotteryDraw>,
+    /// How each signed in voter voted. Kept for the leaderboards, and left out of `/history` so votes stay
+    /// anonymous
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ballots: Vec<Ballot>,
+}
+
+/// A page of `/history`. Pass `next_cursor` as `cursor` to get the next one
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryPage {
+    pub items: Vec<HistoryRecord>,
+    /// None on the last page
+    pub next_cursor: Option<String>,
+}
+
+/// A lottery draw that decided a PR, with everything needed to check it afterwards
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LotteryDraw {
+    pub seed: u64,
+    pub roll: f64,
+    /// The share of votes to accept the PR. It was merged if the roll is below this
+    pub chance: f64,
+}
+
+/// The final vote of a signed in voter on a PR
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Ballot {
+    pub voter: String,
+    pub login: String,
+    pub direction: Direction,
+}
+
+/// The leaderboards served by `/leaderboards`, best first
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Leaderboards {
+    /// Voters by how many PRs they accepted or denied, including ones still in the queue
+    pub votes_cast: Vec<LeaderboardEntry>,
+    /// Voters by how often their vote matched how the PR ended, out of the finalized PRs they voted on
+    pub agreement: Vec<LeaderboardEntry>,
+    /// Voters by the longest run of finalized PRs in a row they called right
+    pub streaks: Vec<LeaderboardEntry>,
+    /// Authors by how many of their PRs were accepted, out of their finalized PRs
+    pub authors: Vec<LeaderboardEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LeaderboardEntry {
+    /// The GitHub login of the voter or author
+    pub name: String,
+    pub score: usize,
+    /// What the score is out of, for leaderboards that rank by a share
+    pub out_of: Option<usize>,
+}
+
+/// A change to the queue, streamed to everyone following `/events`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum QueueEvent {
+    /// A PR was added to the queue
+    Queued { id: PrId },
+    /// A vote was cast, changed or retracted
+    Voted { id: PrId, tally: QueueTally },
+    /// New commits were pushed to a PR. Votes on the old revision no longer count, and `tally` is what is left
+    Updated {
+        pull_request: PullRequest,
+        tally: QueueTally,
+    },
+    /// The PR didn't get enough votes in time, so its vote goes on until `deadline`
+    Extended {
+        id: PrId,
+        deadline: chrono::DateTime<chrono::Utc>,
+    },
+    /// The vote on a PR is over and it left the queue
+    Finalized { id: PrId },
+    /// A PR was merged or closed on GitHub before the vote finished
+    Removed { id: PrId },
+}
+
+/// Narrows down the PRs `/pr` hands out. Sent as query parameters, and every one that is set has to match
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PrFilter {
+    pub repo_owner: Option<String>,
+    pub repo_name: Option<String>,
+    /// Comma separated file extensions, like `rs,toml`. PRs that touch a file with any of them match
+    pub extensions: Option<String>,
+    pub min_additions: Option<usize>,
+    pub max_additions: Option<usize>,
+    pub min_deletions: Option<usize>,
+    pub max_deletions: Option<usize>,
 }
 
 #[cfg(not(feature = "server"))]
@@ -41,6 +272,10 @@ pub enum Direction {
 enum Route {
     #[route("/")]
     Home {},
+    #[route("/archive")]
+    History {},
+    #[route("/leaderboard")]
+    Leaderboard {},
 }
 
 #[cfg(not(feature = "server"))]
@@ -51,6 +286,30 @@ const TAILWIND_CSS: Asset = asset!("/assets/tailwind.css");
 async fn main() {
     use dioxus::logger::tracing::Level;
 
+    // `gitlucky export <file>` and `gitlucky import <file>` back up and restore the configured store
+    let args: Vec<String> = std::env::args().collect();
+    if let [_, command, path] = args.as_slice() {
+        use crate::server::{
+            server::DATA_DIR,
+            store::{export_to, import_from, StoreConfig},
+        };
+        let store = StoreConfig::from_env(DATA_DIR)
+            .open()
+            .expect("Failed to open the store");
+        let result = match command.as_str() {
+            "export" => export_to(&*store, path),
+            "import" => import_from(&*store, path),
+            _ => {
+                println!("Unknown command: {command}. Expected export or import");
+                return;
+            }
+        };
+        if let Err(e) = result {
+            println!("Failed to {command} {path}: {e}");
+        }
+        return;
+    }
+
     println!("Starting server...");
     // let dioxus_logger = dioxus::logger::init(Level::TRACE);
     let mut server = Server::new().await;
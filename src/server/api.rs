@@ -0,0 +1,38 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use crate::{ApiError, ApiErrorCode};
+
+impl ApiErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::VoteClosed | Self::StaleRevision => StatusCode::CONFLICT,
+            Self::InvalidRequest => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            Self::Busy => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.code.status(), Json(self)).into_response()
+    }
+}
+
+/// The store failed. The details are logged instead of sent to the client
+impl From<std::io::Error> for ApiError {
+    fn from(e: std::io::Error) -> Self {
+        println!("Store error: {}", e);
+        Self::new(
+            ApiErrorCode::Internal,
+            "The server couldn't save or load the queue",
+        )
+    }
+}
@@ -0,0 +1,480 @@
+use axum::http::{
+    header::{ACCEPT, AUTHORIZATION, COOKIE, USER_AGENT},
+    HeaderMap,
+};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::Deserialize;
+use sha2::Sha256;
+
+use super::webhook::decode_hex;
+use crate::User;
+
+/// The cookie the signed session of a signed in voter is kept in
+pub const SESSION_COOKIE: &str = "gitlucky_session";
+/// The cookie that ties an OAuth callback to the browser that started the login
+pub const STATE_COOKIE: &str = "gitlucky_oauth_state";
+/// The cookie that identifies a browser that votes without signing in
+pub const DEVICE_COOKIE: &str = "gitlucky_device";
+const SESSION_DAYS: i64 = 30;
+const STATE_MINUTES: i64 = 10;
+const DEVICE_DAYS: i64 = 365;
+
+/// Where to send voters to sign in. Point the URLs at a fake provider to run the flow locally
+#[derive(Debug, Clone)]
+pub struct OAuthConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    pub authorize_url: String,
+    pub token_url: String,
+    pub api_url: String,
+    /// Where the provider sends voters back to. GitHub uses the callback URL of the OAuth app if this is unset
+    pub redirect_url: Option<String>,
+}
+
+impl OAuthConfig {
+    /// Read the OAuth app from the environment. Sign in is disabled without `GITHUB_CLIENT_ID`
+    pub fn from_env() -> Option<Self> {
+        let client_id = std::env::var("GITHUB_CLIENT_ID").ok()?;
+        let client_secret = std::env::var("GITHUB_CLIENT_SECRET")
+            .expect("Missing environment variable: GITHUB_CLIENT_SECRET");
+        let oauth_url =
+            std::env::var("GITHUB_OAUTH_URL").unwrap_or_else(|_| "https://github.com".to_string());
+        let api_url = std::env::var("GITHUB_API_URL")
+            .unwrap_or_else(|_| "https://api.github.com".to_string());
+        Some(Self {
+            client_id,
+            client_secret,
+            authorize_url: format!("{oauth_url}/login/oauth/authorize"),
+            token_url: format!("{oauth_url}/login/oauth/access_token"),
+            api_url,
+            redirect_url: public_url().map(|url| format!("{url}/auth/callback")),
+        })
+    }
+}
+
+/// The URL the server is reachable at, if configured
+fn public_url() -> Option<String> {
+    std::env::var("GITLUCKY_PUBLIC_URL")
+        .ok()
+        .map(|url| url.trim_end_matches('/').to_string())
+}
+
+/// Signs values handed to browsers so they can't be forged
+#[derive(Clone)]
+pub struct Signer {
+    key: Vec<u8>,
+}
+
+impl Signer {
+    pub fn new(key: &[u8]) -> Self {
+        Self { key: key.to_vec() }
+    }
+
+    /// Sign with `GITLUCKY_SESSION_SECRET`. Without it, every restart signs everyone out
+    pub fn from_env() -> Self {
+        match std::env::var("GITLUCKY_SESSION_SECRET") {
+            Ok(secret) => Self::new(secret.as_bytes()),
+            Err(_) => {
+                println!("GITLUCKY_SESSION_SECRET is not set, sessions won't survive a restart");
+                Self::new(&rand::rng().random::<[u8; 32]>())
+            }
+        }
+    }
+
+    fn mac(&self, payload: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    /// Append a signature to `payload`
+    pub fn sign(&self, payload: &str) -> String {
+        let signature = self.mac(payload).finalize().into_bytes();
+        format!("{payload}.{}", encode_hex(&signature))
+    }
+
+    /// The payload of a value from [`Signer::sign`], if the signature matches
+    pub fn verify<'a>(&self, signed: &'a str) -> Option<&'a str> {
+        let (payload, signature) = signed.rsplit_once('.')?;
+        let signature = decode_hex(signature)?;
+        self.mac(payload).verify_slice(&signature).ok()?;
+        Some(payload)
+    }
+}
+
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// The value of the cookie `name` in a request
+pub fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|pair| {
+            let (key, value) = pair.trim().split_once('=')?;
+            (key == name).then_some(value)
+        })
+}
+
+/// A `Set-Cookie` value for an HttpOnly cookie. A `max_age` of zero deletes it
+pub fn set_cookie(name: &str, value: &str, max_age: chrono::Duration) -> String {
+    let secure = if public_url().is_some_and(|url| url.starts_with("https://")) {
+        "; Secure"
+    } else {
+        ""
+    };
+    format!(
+        "{name}={value}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}{secure}",
+        max_age.num_seconds()
+    )
+}
+
+/// The query GitHub sends voters back to `/auth/callback` with
+#[derive(Debug, Deserialize)]
+pub struct CallbackQuery {
+    pub code: String,
+    pub state: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+/// Whoever cast a vote
+#[derive(Debug, Clone, PartialEq)]
+pub enum Voter {
+    User(User),
+    /// A browser that didn't sign in, identified by its device token
+    Device(String),
+}
+
+impl Voter {
+    /// The key the votes of this voter are recorded under
+    pub fn id(&self) -> String {
+        match self {
+            Self::User(user) => format!("github:{}", user.id),
+            Self::Device(device) => format!("device:{device}"),
+        }
+    }
+
+    /// The GitHub login of a signed in voter
+    pub fn login(&self) -> Option<&str> {
+        match self {
+            Self::User(user) => Some(&user.login),
+            Self::Device(_) => None,
+        }
+    }
+}
+
+/// Whether a vote recorded under `voter_id` was cast without signing in
+pub fn is_anonymous(voter_id: &str) -> bool {
+    voter_id.starts_with("device:")
+}
+
+/// Signs voters in with GitHub and keeps track of who they are
+#[derive(Clone)]
+pub struct Auth {
+    oauth: Option<OAuthConfig>,
+    signer: Signer,
+    http: reqwest::Client,
+}
+
+impl Auth {
+    pub fn new(oauth: Option<OAuthConfig>, signer: Signer) -> Self {
+        if oauth.is_none() {
+            println!("GITHUB_CLIENT_ID is not set, voters can't sign in");
+        }
+        Self {
+            oauth,
+            signer,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// Start signing in. Returns where to send the voter and the `Set-Cookie` value for the state cookie
+    pub fn login(&self) -> Option<(String, String)> {
+        let oauth = self.oauth.as_ref()?;
+        let state = encode_hex(&rand::rng().random::<[u8; 16]>());
+        let mut params = vec![
+            ("client_id", oauth.client_id.as_str()),
+            ("state", state.as_str()),
+        ];
+        if let Some(redirect_url) = &oauth.redirect_url {
+            params.push(("redirect_uri", redirect_url.as_str()));
+        }
+        let url = reqwest::Url::parse_with_params(&oauth.authorize_url, &params).unwrap();
+        let cookie = set_cookie(
+            STATE_COOKIE,
+            &state,
+            chrono::Duration::minutes(STATE_MINUTES),
+        );
+        Some((url.to_string(), cookie))
+    }
+
+    /// Finish signing in with the code the provider sent back. Returns the user and the `Set-Cookie` value for
+    /// their session
+    pub async fn callback(
+        &self,
+        headers: &HeaderMap,
+        code: &str,
+        state: &str,
+    ) -> Result<(User, String), String> {
+        let Some(oauth) = &self.oauth else {
+            return Err("sign in is disabled".to_string());
+        };
+        if cookie(headers, STATE_COOKIE) != Some(state) {
+            return Err("the state doesn't match the one this browser started with".to_string());
+        }
+
+        let mut body = serde_json::json!({
+            "client_id": oauth.client_id,
+            "client_secret": oauth.client_secret,
+            "code": code,
+        });
+        if let Some(redirect_url) = &oauth.redirect_url {
+            body["redirect_uri"] = redirect_url.clone().into();
+        }
+        let token: TokenResponse = self
+            .http
+            .post(&oauth.token_url)
+            .header(ACCEPT, "application/json")
+            .json(&body)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())?;
+        let Some(access_token) = token.access_token else {
+            return Err(format!(
+                "no access token: {} {}",
+                token.error.unwrap_or_default(),
+                token.error_description.unwrap_or_default()
+            ));
+        };
+
+        let user: User = self
+            .http
+            .get(format!("{}/user", oauth.api_url))
+            .header(AUTHORIZATION, format!("Bearer {access_token}"))
+            .header(ACCEPT, "application/vnd.github+json")
+            .header(USER_AGENT, "gitlucky")
+            .send()
+            .await
+            .map_err(|e| e.to_string())?
+            .error_for_status()
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let expires = chrono::Utc::now() + chrono::Duration::days(SESSION_DAYS);
+        let session = self.signer.sign(&format!(
+            "{}.{}.{}",
+            user.id,
+            user.login,
+            expires.timestamp()
+        ));
+        let cookie = set_cookie(
+            SESSION_COOKIE,
+            &session,
+            chrono::Duration::days(SESSION_DAYS),
+        );
+        Ok((user, cookie))
+    }
+
+    /// A `Set-Cookie` value for a new device token
+    pub fn issue_device(&self) -> String {
+        let device = encode_hex(&rand::rng().random::<[u8; 16]>());
+        let token = self.signer.sign(&format!("device:{device}"));
+        set_cookie(DEVICE_COOKIE, &token, chrono::Duration::days(DEVICE_DAYS))
+    }
+
+    /// The device token a request was sent with, if it was issued by this server
+    pub fn device(&self, headers: &HeaderMap) -> Option<String> {
+        let token = self.signer.verify(cookie(headers, DEVICE_COOKIE)?)?;
+        Some(token.strip_prefix("device:")?.to_string())
+    }
+
+    /// Who sent a request. Signed in voters take precedence over their device
+    pub fn voter(&self, headers: &HeaderMap) -> Option<Voter> {
+        self.user(headers)
+            .map(Voter::User)
+            .or_else(|| self.device(headers).map(Voter::Device))
+    }
+
+    /// The signed in user that sent a request
+    pub fn user(&self, headers: &HeaderMap) -> Option<User> {
+        let session = self.signer.verify(cookie(headers, SESSION_COOKIE)?)?;
+        let mut parts = session.split('.');
+        let (Some(id), Some(login), Some(expires), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return None;
+        };
+        if expires.parse::<i64>().ok()? < chrono::Utc::now().timestamp() {
+            return None;
+        }
+        Some(User {
+            id: id.parse().ok()?,
+            login: login.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use axum::{
+        routing::{get, post},
+        Json, Router,
+    };
+
+    /// The code the fake provider accepts, and the account it signs in
+    const CODE: &str = "fake-code";
+    const ACCESS_TOKEN: &str = "fake-access-token";
+
+    /// Start an OAuth provider on a random port that signs everyone in as octocat, and point a config at it
+    pub(crate) async fn fake_provider() -> OAuthConfig {
+        let router = Router::new()
+            .route(
+                "/login/oauth/access_token",
+                post(|Json(body): Json<serde_json::Value>| async move {
+                    if body["client_secret"] == "fake-secret" && body["code"] == CODE {
+                        Json(serde_json::json!({ "access_token": ACCESS_TOKEN }))
+                    } else {
+                        Json(serde_json::json!({
+                            "error": "bad_verification_code",
+                            "error_description": "The code passed is incorrect or expired."
+                        }))
+                    }
+                }),
+            )
+            .route(
+                "/user",
+                get(|headers: HeaderMap| async move {
+                    let expected = format!("Bearer {ACCESS_TOKEN}");
+                    if headers
+                        .get(AUTHORIZATION)
+                        .and_then(|value| value.to_str().ok())
+                        != Some(expected.as_str())
+                    {
+                        return Err(axum::http::StatusCode::UNAUTHORIZED);
+                    }
+                    Ok(Json(User {
+                        id: 583231,
+                        login: "octocat".to_string(),
+                    }))
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        OAuthConfig {
+            client_id: "fake-client".to_string(),
+            client_secret: "fake-secret".to_string(),
+            authorize_url: format!("{url}/login/oauth/authorize"),
+            token_url: format!("{url}/login/oauth/access_token"),
+            api_url: url,
+            redirect_url: None,
+        }
+    }
+
+    /// The headers of a request that sends back the cookie from a `Set-Cookie` value
+    pub(crate) fn with_cookie(set_cookie: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let cookie = set_cookie.split(';').next().unwrap();
+        headers.insert(COOKIE, cookie.parse().unwrap());
+        headers
+    }
+
+    /// Go through the whole sign in, the way a browser would. Returns the `Set-Cookie` value of the session
+    pub(crate) async fn sign_in(auth: &Auth) -> String {
+        let (url, state_cookie) = auth.login().unwrap();
+        let url = reqwest::Url::parse(&url).unwrap();
+        let state = url
+            .query_pairs()
+            .find(|(key, _)| key == "state")
+            .unwrap()
+            .1
+            .to_string();
+        let (_, session_cookie) = auth
+            .callback(&with_cookie(&state_cookie), CODE, &state)
+            .await
+            .unwrap();
+        session_cookie
+    }
+
+    #[tokio::test]
+    async fn signs_in_through_the_provider() {
+        let auth = Auth::new(Some(fake_provider().await), Signer::new(b"test-secret"));
+        let (url, _) = auth.login().unwrap();
+        assert!(url.contains("/login/oauth/authorize?client_id=fake-client&state="));
+
+        let session = with_cookie(&sign_in(&auth).await);
+        let octocat = User {
+            id: 583231,
+            login: "octocat".to_string(),
+        };
+        assert_eq!(auth.user(&session), Some(octocat.clone()));
+        assert_eq!(auth.voter(&session), Some(Voter::User(octocat)));
+    }
+
+    #[tokio::test]
+    async fn rejects_a_callback_from_another_browser() {
+        let auth = Auth::new(Some(fake_provider().await), Signer::new(b"test-secret"));
+        let (_, state_cookie) = auth.login().unwrap();
+        let error = auth
+            .callback(&with_cookie(&state_cookie), CODE, "someone-elses-state")
+            .await
+            .unwrap_err();
+        assert!(error.contains("state"), "{error}");
+        // No state cookie at all
+        let error = auth
+            .callback(&HeaderMap::new(), CODE, "someone-elses-state")
+            .await
+            .unwrap_err();
+        assert!(error.contains("state"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn rejects_a_code_the_provider_refuses() {
+        let auth = Auth::new(Some(fake_provider().await), Signer::new(b"test-secret"));
+        let (url, state_cookie) = auth.login().unwrap();
+        let url = reqwest::Url::parse(&url).unwrap();
+        let (_, state) = url.query_pairs().find(|(key, _)| key == "state").unwrap();
+        let error = auth
+            .callback(&with_cookie(&state_cookie), "stolen-code", &state)
+            .await
+            .unwrap_err();
+        assert!(error.contains("bad_verification_code"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn ignores_sessions_signed_with_another_key() {
+        let auth = Auth::new(Some(fake_provider().await), Signer::new(b"test-secret"));
+        let session = with_cookie(&sign_in(&auth).await);
+        let other = Auth::new(None, Signer::new(b"another-secret"));
+        assert_eq!(other.user(&session), None);
+    }
+
+    #[test]
+    fn devices_vote_with_the_token_they_were_issued() {
+        let auth = Auth::new(None, Signer::new(b"test-secret"));
+        let headers = with_cookie(&auth.issue_device());
+        let device = auth.device(&headers).unwrap();
+        assert_eq!(auth.voter(&headers), Some(Voter::Device(device.clone())));
+        assert!(is_anonymous(&Voter::Device(device).id()));
+
+        let forged = with_cookie(&format!("{DEVICE_COOKIE}=device:1234"));
+        assert_eq!(auth.voter(&forged), None);
+    }
+}
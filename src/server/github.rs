@@ -0,0 +1,55 @@
+use std::{future::Future, pin::Pin};
+
+use super::server::PullRequestInfo;
+use crate::{github_bot::bot, PullRequest};
+
+type GitHubFuture<T = ()> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// What the server does on GitHub once a vote ends. Swapping this out lets tests see what would have happened
+pub trait GitHub: Send + Sync + 'static {
+    /// Merge the PR and comment `comment` on it. Resolves to the SHA of the merge commit, or why it couldn't be
+    /// merged
+    fn merge(&self, pr: PullRequestInfo, comment: String) -> GitHubFuture<Result<String, String>>;
+
+    /// Close the PR and comment `comment` on it. Resolves to why it couldn't be closed, if it wasn't
+    fn deny_merge(&self, pr: PullRequestInfo, comment: String) -> GitHubFuture<Result<(), String>>;
+
+    /// Leave a comment on a PR
+    fn comment(&self, pull_request: &PullRequest, comment: String) -> GitHubFuture;
+}
+
+/// GitHub itself, through the GitHub App
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bot;
+
+impl GitHub for Bot {
+    fn merge(&self, pr: PullRequestInfo, comment: String) -> GitHubFuture<Result<String, String>> {
+        Box::pin(async move {
+            let merged = bot::merge(pr, comment).await;
+            if let Err(e) = &merged {
+                println!("Failed to merge PR: {}", e);
+            }
+            merged
+        })
+    }
+
+    fn deny_merge(&self, pr: PullRequestInfo, comment: String) -> GitHubFuture<Result<(), String>> {
+        Box::pin(async move {
+            let closed = bot::deny_merge(pr, comment).await;
+            if let Err(e) = &closed {
+                println!("Failed to close PR: {}", e);
+            }
+            closed
+        })
+    }
+
+    fn comment(&self, pull_request: &PullRequest, comment: String) -> GitHubFuture {
+        let PullRequest {
+            repo_owner,
+            repo_name,
+            pr_number,
+            ..
+        } = pull_request.clone();
+        Box::pin(async move { bot::comment(&repo_owner, &repo_name, pr_number, comment).await })
+    }
+}
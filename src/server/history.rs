@@ -0,0 +1,214 @@
+use serde::Deserialize;
+
+use super::{auth::encode_hex, queue::page_size, webhook::decode_hex};
+use crate::{ApiError, ApiErrorCode, HistoryPage, HistoryRecord, Outcome};
+
+/// The query parameters of `/history`. Every filter that is set has to match
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct HistoryQuery {
+    pub repo_owner: Option<String>,
+    pub repo_name: Option<String>,
+    pub outcome: Option<Outcome>,
+    /// How many records to return, up to 200
+    pub limit: Option<usize>,
+    /// The `next_cursor` of the previous page
+    pub cursor: Option<String>,
+}
+
+/// Where a record is in the history. Records are listed by when they were finalized, and ties are broken by the
+/// PR id, so records finalized at the same time are never skipped or listed twice across pages
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Cursor {
+    finalized_time: i64,
+    id: String,
+}
+
+impl Cursor {
+    fn of(record: &HistoryRecord) -> Self {
+        Self {
+            finalized_time: record.finalized_time.timestamp_micros(),
+            id: format!("{}@{}", record.id.key(), record.id.revision),
+        }
+    }
+
+    fn encode(&self) -> String {
+        encode_hex(format!("{}:{}", self.finalized_time, self.id).as_bytes())
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        let cursor = String::from_utf8(decode_hex(cursor)?).ok()?;
+        let (finalized_time, id) = cursor.split_once(':')?;
+        Some(Self {
+            finalized_time: finalized_time.parse().ok()?,
+            id: id.to_string(),
+        })
+    }
+}
+
+/// One page of the finalized PRs that match `query`, newest first
+pub fn search(history: Vec<HistoryRecord>, query: &HistoryQuery) -> Result<HistoryPage, ApiError> {
+    let limit = page_size(query.limit)?;
+    let after = match &query.cursor {
+        Some(cursor) => Some(Cursor::decode(cursor).ok_or_else(|| {
+            ApiError::new(
+                ApiErrorCode::InvalidRequest,
+                "cursor is not from a page of /history",
+            )
+        })?),
+        None => None,
+    };
+    let same = |filter: &Option<String>, value: &str| {
+        filter
+            .as_deref()
+            .is_none_or(|filter| filter.eq_ignore_ascii_case(value))
+    };
+
+    let mut records: Vec<(Cursor, HistoryRecord)> = history
+        .into_iter()
+        .filter(|record| {
+            same(&query.repo_owner, &record.id.owner)
+                && same(&query.repo_name, &record.id.repo)
+                && query
+                    .outcome
+                    .is_none_or(|outcome| record.outcome == outcome)
+        })
+        .map(|record| (Cursor::of(&record), record))
+        .filter(|(cursor, _)| after.as_ref().is_none_or(|after| cursor < after))
+        .collect();
+    records.sort_by(|(a, _), (b, _)| b.cmp(a));
+
+    let more = records.len() > limit;
+    records.truncate(limit);
+    let next_cursor = records
+        .last()
+        .filter(|_| more)
+        .map(|(cursor, _)| cursor.encode());
+    let items = records.into_iter().map(|(_, record)| record).collect();
+    Ok(HistoryPage { items, next_cursor })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PrId, QueueTally};
+
+    fn now() -> chrono::DateTime<chrono::Utc> {
+        "2025-01-02T12:00:00Z".parse().unwrap()
+    }
+
+    /// PR `number` of octocat/`repo`, finalized `minutes` minutes after [`now`]
+    fn record(repo: &str, number: u64, minutes: i64, outcome: Outcome) -> HistoryRecord {
+        HistoryRecord {
+            id: PrId {
+                owner: "octocat".to_string(),
+                repo: repo.to_string(),
+                number,
+                revision: "6dcb09b5b57875f334f61aebed695e2e4193db5e".to_string(),
+            },
+            title: format!("PR {number}"),
+            author: "octocat".to_string(),
+            outcome,
+            tally: QueueTally {
+                accepted: 1,
+                denied: 0,
+                abstained: 0,
+            },
+            policy: "majority".to_string(),
+            comment: String::new(),
+            merge_sha: None,
+            error: None,
+            finalized_time: now() + chrono::Duration::minutes(minutes),
+            lottery: None,
+            ballots: Vec::new(),
+        }
+    }
+
+    /// Oldest first, like the store keeps it. PRs 2, 3 and 4 were finalized at the same time
+    fn history() -> Vec<HistoryRecord> {
+        vec![
+            record("hello-world", 1, 0, Outcome::Merged),
+            record("hello-world", 3, 5, Outcome::Closed),
+            record("hello-world", 2, 5, Outcome::Merged),
+            record("spoon-knife", 4, 5, Outcome::Merged),
+            record("hello-world", 5, 9, Outcome::LeftOpen),
+        ]
+    }
+
+    fn numbers(page: &HistoryPage) -> Vec<u64> {
+        page.items.iter().map(|record| record.id.number).collect()
+    }
+
+    #[test]
+    fn the_newest_records_come_first() {
+        let page = search(history(), &HistoryQuery::default()).unwrap();
+        assert_eq!(numbers(&page), [5, 4, 3, 2, 1]);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn pages_dont_skip_records_finalized_at_the_same_time() {
+        let mut query = HistoryQuery {
+            limit: Some(2),
+            ..HistoryQuery::default()
+        };
+        let mut pages = Vec::new();
+        loop {
+            let page = search(history(), &query).unwrap();
+            pages.push(numbers(&page));
+            match page.next_cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => break,
+            }
+        }
+        assert_eq!(pages, [vec![5, 4], vec![3, 2], vec![1]]);
+    }
+
+    #[test]
+    fn filters_narrow_down_the_records() {
+        let search = |query: HistoryQuery| numbers(&search(history(), &query).unwrap());
+        assert_eq!(
+            search(HistoryQuery {
+                repo_name: Some("Spoon-Knife".to_string()),
+                ..HistoryQuery::default()
+            }),
+            [4]
+        );
+        assert_eq!(
+            search(HistoryQuery {
+                repo_owner: Some("octocat".to_string()),
+                outcome: Some(Outcome::Merged),
+                ..HistoryQuery::default()
+            }),
+            [4, 2, 1]
+        );
+        assert!(search(HistoryQuery {
+            repo_owner: Some("someone-else".to_string()),
+            ..HistoryQuery::default()
+        })
+        .is_empty());
+    }
+
+    #[test]
+    fn bad_cursors_and_limits_are_refused() {
+        for query in [
+            HistoryQuery {
+                cursor: Some("zz".to_string()),
+                ..HistoryQuery::default()
+            },
+            HistoryQuery {
+                cursor: Some(encode_hex(b"yesterday:octocat/hello-world#1")),
+                ..HistoryQuery::default()
+            },
+            HistoryQuery {
+                limit: Some(0),
+                ..HistoryQuery::default()
+            },
+        ] {
+            assert_eq!(
+                search(history(), &query).unwrap_err().code,
+                ApiErrorCode::InvalidRequest
+            );
+        }
+    }
+}
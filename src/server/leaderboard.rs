@@ -0,0 +1,247 @@
+use std::{cmp::Ordering, collections::HashMap};
+
+use super::server::PullRequestInfo;
+use crate::{Direction, HistoryRecord, LeaderboardEntry, Leaderboards, Outcome};
+
+/// How many entries each leaderboard has
+const LEADERBOARD_SIZE: usize = 10;
+/// How many finalized PRs a voter has to have voted on before they show up on the agreement leaderboard
+const MIN_AGREEMENT_VOTES: usize = 5;
+
+/// How a signed in voter has done so far
+#[derive(Debug, Default)]
+struct VoterStats {
+    login: String,
+    votes_cast: usize,
+    /// Votes on finalized PRs that ended up merged or closed
+    decided: usize,
+    agreed: usize,
+    streak: usize,
+    longest_streak: usize,
+}
+
+/// Rank voters by the archive of finalized PRs and the votes on the queue
+///
+/// Only signed in voters are ranked, under the login they last voted with. Skips don't count as votes, and PRs
+/// left for the maintainers don't count toward agreement or streaks.
+pub fn compute(history: &[HistoryRecord], queue: &[PullRequestInfo]) -> Leaderboards {
+    let mut voters: HashMap<&str, VoterStats> = HashMap::new();
+    let mut authors: HashMap<&str, (usize, usize)> = HashMap::new();
+
+    let mut history: Vec<&HistoryRecord> = history.iter().collect();
+    history.sort_by_key(|record| record.finalized_time);
+    for record in history {
+        let result = match record.outcome {
+            Outcome::Merged | Outcome::MergeFailed => Some(Direction::Right),
+            Outcome::Closed => Some(Direction::Left),
+            Outcome::LeftOpen => None,
+        };
+        if let Some(result) = result {
+            let (accepted, finalized) = authors.entry(record.author.as_str()).or_default();
+            *finalized += 1;
+            if result == Direction::Right {
+                *accepted += 1;
+            }
+        }
+
+        for ballot in &record.ballots {
+            if ballot.direction == Direction::Skip {
+                continue;
+            }
+            let stats = voters.entry(ballot.voter.as_str()).or_default();
+            stats.login = ballot.login.clone();
+            stats.votes_cast += 1;
+            let Some(result) = result else {
+                continue;
+            };
+            stats.decided += 1;
+            if ballot.direction == result {
+                stats.agreed += 1;
+                stats.streak += 1;
+                stats.longest_streak = stats.longest_streak.max(stats.streak);
+            } else {
+                stats.streak = 0;
+            }
+        }
+    }
+
+    for pr in queue {
+        for (voter, &direction) in &pr.votes {
+            let Some(login) = pr.login(voter) else {
+                continue;
+            };
+            if direction == Direction::Skip {
+                continue;
+            }
+            let stats = voters.entry(voter.as_str()).or_default();
+            stats.login = login.to_string();
+            stats.votes_cast += 1;
+        }
+    }
+
+    let voters: Vec<VoterStats> = voters.into_values().collect();
+    Leaderboards {
+        votes_cast: rank(
+            voters
+                .iter()
+                .map(|stats| entry(&stats.login, stats.votes_cast, None)),
+            false,
+        ),
+        agreement: rank(
+            voters
+                .iter()
+                .filter(|stats| stats.decided >= MIN_AGREEMENT_VOTES)
+                .map(|stats| entry(&stats.login, stats.agreed, Some(stats.decided))),
+            true,
+        ),
+        streaks: rank(
+            voters
+                .iter()
+                .map(|stats| entry(&stats.login, stats.longest_streak, None)),
+            false,
+        ),
+        authors: rank(
+            authors
+                .into_iter()
+                .map(|(author, (accepted, finalized))| entry(author, accepted, Some(finalized))),
+            false,
+        ),
+    }
+}
+
+fn entry(name: &str, score: usize, out_of: Option<usize>) -> LeaderboardEntry {
+    LeaderboardEntry {
+        name: name.to_string(),
+        score,
+        out_of,
+    }
+}
+
+/// The best [`LEADERBOARD_SIZE`] entries with a score, by score or by the share of `out_of` it is. Ties go to
+/// the other one and then by name
+fn rank(entries: impl Iterator<Item = LeaderboardEntry>, by_share: bool) -> Vec<LeaderboardEntry> {
+    let mut entries: Vec<LeaderboardEntry> = entries.filter(|entry| entry.score > 0).collect();
+    entries.sort_by(|a, b| {
+        // a.score / a.out_of against b.score / b.out_of, without dividing
+        let share = match (a.out_of, b.out_of) {
+            (Some(a_out_of), Some(b_out_of)) => (b.score * a_out_of).cmp(&(a.score * b_out_of)),
+            _ => Ordering::Equal,
+        };
+        let score = b.score.cmp(&a.score);
+        let order = if by_share {
+            share.then(score)
+        } else {
+            score.then(share)
+        };
+        order.then_with(|| a.name.cmp(&b.name))
+    });
+    entries.truncate(LEADERBOARD_SIZE);
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Ballot;
+
+    /// PR `number`, finalized `minutes` after the fixture PR with `outcome`, and how each of `ballots` voted
+    fn record(
+        number: u64,
+        minutes: i64,
+        outcome: Outcome,
+        ballots: &[(&str, Direction)],
+    ) -> HistoryRecord {
+        let record: HistoryRecord =
+            serde_json::from_str(include_str!("fixtures/finalized_pr.json")).unwrap();
+        let mut id = record.id.clone();
+        id.number = number;
+        HistoryRecord {
+            id,
+            outcome,
+            finalized_time: record.finalized_time + chrono::Duration::minutes(minutes),
+            ballots: ballots
+                .iter()
+                .map(|&(login, direction)| Ballot {
+                    voter: format!("github:{login}"),
+                    login: login.to_string(),
+                    direction,
+                })
+                .collect(),
+            ..record
+        }
+    }
+
+    fn scores(leaderboard: &[LeaderboardEntry]) -> Vec<(&str, usize, Option<usize>)> {
+        leaderboard
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.score, entry.out_of))
+            .collect()
+    }
+
+    #[test]
+    fn agreement_needs_enough_decided_votes() {
+        let mut history: Vec<HistoryRecord> = (0..5)
+            .map(|i| {
+                record(
+                    i,
+                    i as i64,
+                    Outcome::Merged,
+                    &[("alice", Direction::Right), ("bob", Direction::Right)],
+                )
+            })
+            .collect();
+        // Bob missed one, and PRs left open or skipped don't count
+        history[4].ballots.pop();
+        history.push(record(
+            5,
+            5,
+            Outcome::Closed,
+            &[("alice", Direction::Right), ("bob", Direction::Skip)],
+        ));
+        history.push(record(
+            6,
+            6,
+            Outcome::LeftOpen,
+            &[("alice", Direction::Left), ("bob", Direction::Left)],
+        ));
+
+        let leaderboards = compute(&history, &[]);
+        assert_eq!(scores(&leaderboards.agreement), [("alice", 5, Some(6))]);
+        assert_eq!(
+            scores(&leaderboards.votes_cast),
+            [("alice", 7, None), ("bob", 5, None)]
+        );
+    }
+
+    #[test]
+    fn streaks_follow_the_order_prs_were_finalized_in() {
+        // Listed out of order: alice calls 0, 1 and 2 right, misses 3 and calls 4 right
+        let history = [
+            record(4, 4, Outcome::Merged, &[("alice", Direction::Right)]),
+            record(3, 3, Outcome::MergeFailed, &[("alice", Direction::Left)]),
+            record(2, 2, Outcome::Closed, &[("alice", Direction::Left)]),
+            record(1, 1, Outcome::LeftOpen, &[("alice", Direction::Right)]),
+            record(0, 0, Outcome::Merged, &[("alice", Direction::Right)]),
+            record(5, 5, Outcome::Closed, &[("bob", Direction::Right)]),
+        ];
+        let leaderboards = compute(&history, &[]);
+        // The PR left open neither extends nor breaks the streak, and bob never called one right
+        assert_eq!(scores(&leaderboards.streaks), [("alice", 2, None)]);
+    }
+
+    #[test]
+    fn authors_are_ranked_by_their_accepted_prs() {
+        let mut history = vec![
+            record(0, 0, Outcome::Merged, &[]),
+            record(1, 1, Outcome::Closed, &[]),
+            record(2, 2, Outcome::LeftOpen, &[]),
+        ];
+        let mut hubot = record(3, 3, Outcome::MergeFailed, &[]);
+        hubot.author = "hubot".to_string();
+        history.push(hubot);
+        assert_eq!(
+            scores(&compute(&history, &[]).authors),
+            [("hubot", 1, Some(1)), ("octocat", 1, Some(2))]
+        );
+    }
+}
@@ -0,0 +1,333 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{LotteryDraw, PrId};
+
+/// How a repo turns the votes on a PR into a decision
+///
+/// Every policy counts a vote with its weight: 1 for signed in voters, and the `anonymous_weight` of the repo for
+/// everyone else
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum DecisionPolicy {
+    /// Merge if there are more right swipes than left swipes
+    #[default]
+    Majority,
+    /// Merge if at least `percent` of the votes are right swipes
+    Supermajority { percent: f64 },
+    /// Merge with a chance equal to the share of right swipes
+    Lottery,
+}
+
+/// The votes on a PR when the vote closes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tally {
+    pub left_votes: usize,
+    pub right_votes: usize,
+    pub left_weight: f64,
+    pub right_weight: f64,
+}
+
+impl Tally {
+    /// The votes that count toward the outcome, each with its weight
+    pub fn weight(&self) -> f64 {
+        self.left_weight + self.right_weight
+    }
+
+    /// The share of the weight that went to accepting the PR, or 0 if no vote carries any weight
+    fn right_share(&self) -> f64 {
+        if self.weight() > 0.0 {
+            self.right_weight / self.weight()
+        } else {
+            0.0
+        }
+    }
+}
+
+/// The roll is the first 8 bytes of `sha256("{seed}:{owner}/{repo}#{number}:{head_sha}")` as a big endian
+/// integer, divided by 2^64. The PR is merged if the roll is below the chance.
+impl LotteryDraw {
+    /// A seed for a PR that will be decided by lottery. It is drawn when the PR is queued, and only its
+    /// [`commitment`](Self::commitment) is published until the vote ends
+    pub fn new_seed() -> u64 {
+        rand::rng().random()
+    }
+
+    /// The hash of `seed` that is posted on the PR when it is queued, so the seed can't be changed afterwards
+    /// without anyone noticing
+    pub fn commitment(seed: u64) -> String {
+        format!("{:x}", Sha256::digest(seed.to_string()))
+    }
+
+    pub fn draw(chance: f64, id: &PrId, seed: u64) -> Self {
+        Self {
+            seed,
+            roll: Self::roll(seed, id),
+            chance,
+        }
+    }
+
+    pub fn roll(seed: u64, id: &PrId) -> f64 {
+        let hash = Sha256::digest(Self::input(seed, id));
+        let bytes: [u8; 8] = hash[..8].try_into().unwrap();
+        u64::from_be_bytes(bytes) as f64 / 2f64.powi(64)
+    }
+
+    /// What gets hashed for the roll
+    pub fn input(seed: u64, id: &PrId) -> String {
+        format!("{seed}:{}:{}", id.key(), id.revision)
+    }
+
+    pub fn won(&self) -> bool {
+        self.roll < self.chance
+    }
+}
+
+/// The outcome of a policy and a sentence explaining how it was reached
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decision {
+    pub accepted: bool,
+    pub explanation: String,
+    /// The draw that decided it, for the `lottery` policy
+    pub lottery: Option<LotteryDraw>,
+}
+
+impl std::fmt::Display for DecisionPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Majority => write!(f, "majority"),
+            Self::Supermajority { percent } => write!(f, "supermajority of {percent}%"),
+            Self::Lottery => write!(f, "lottery"),
+        }
+    }
+}
+
+impl DecisionPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Self::Supermajority { percent } if !(50.0..=100.0).contains(percent) => Err(format!(
+                "`policy.percent` must be between 50 and 100, found {percent}"
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the votes are split evenly in a way this policy can't decide. Supermajorities and lotteries
+    /// always reach a decision
+    pub fn is_tie(&self, tally: Tally) -> bool {
+        match self {
+            Self::Majority => tally.left_weight == tally.right_weight,
+            Self::Supermajority { .. } | Self::Lottery => false,
+        }
+    }
+
+    /// Decide on a PR. The revision in `id` goes into lottery draws, and `lottery_seed` is the seed the PR
+    /// committed to when it was queued. Without one a fresh seed is drawn
+    pub fn decide(&self, tally: Tally, id: &PrId, lottery_seed: Option<u64>) -> Decision {
+        match self {
+            Self::Majority => Decision {
+                accepted: tally.right_weight > tally.left_weight,
+                explanation: format!(
+                    "The majority decides: {:.2} for accepting, {:.2} for denying.",
+                    tally.right_weight, tally.left_weight
+                ),
+                lottery: None,
+            },
+            Self::Supermajority { percent } => {
+                let share = 100.0 * tally.right_share();
+                Decision {
+                    accepted: tally.weight() > 0.0 && share >= *percent,
+                    explanation: format!(
+                        "{share:.1}% accepted, and {percent}% is needed to merge."
+                    ),
+                    lottery: None,
+                }
+            }
+            Self::Lottery => {
+                let chance = tally.right_share();
+                let draw = LotteryDraw::draw(
+                    chance,
+                    id,
+                    lottery_seed.unwrap_or_else(LotteryDraw::new_seed),
+                );
+                println!("Lottery draw for {}: {:?}", id, draw);
+                let mut explanation = format!(
+                    "Feeling lucky! This PR had a {:.1}% chance of being merged and rolled {:.4} (seed `{}`, roll = first 8 bytes of `sha256(\"{}\")` / 2^64).",
+                    100.0 * chance,
+                    draw.roll,
+                    draw.seed,
+                    LotteryDraw::input(draw.seed, id)
+                );
+                if lottery_seed.is_some() {
+                    explanation += &format!(
+                        " The seed matches the hash `{}` posted when the vote opened.",
+                        LotteryDraw::commitment(draw.seed)
+                    );
+                }
+                Decision {
+                    accepted: draw.won(),
+                    explanation,
+                    lottery: Some(draw),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEAD_SHA: &str = "6dcb09b5b57875f334f61aebed695e2e4193db5e";
+
+    fn id() -> PrId {
+        PrId {
+            owner: "octocat".to_string(),
+            repo: "hello-world".to_string(),
+            number: 7,
+            revision: HEAD_SHA.to_string(),
+        }
+    }
+
+    fn tally(left_votes: usize, right_votes: usize) -> Tally {
+        Tally {
+            left_votes,
+            right_votes,
+            left_weight: left_votes as f64,
+            right_weight: right_votes as f64,
+        }
+    }
+
+    fn decide(policy: DecisionPolicy, tally: Tally) -> Decision {
+        policy.decide(tally, &id(), Some(42))
+    }
+
+    #[test]
+    fn the_majority_needs_more_right_swipes() {
+        assert!(decide(DecisionPolicy::Majority, tally(1, 2)).accepted);
+        assert!(!decide(DecisionPolicy::Majority, tally(2, 2)).accepted);
+        assert!(!decide(DecisionPolicy::Majority, tally(0, 0)).accepted);
+    }
+
+    #[test]
+    fn a_supermajority_needs_its_share_of_the_votes() {
+        let two_thirds = DecisionPolicy::Supermajority { percent: 66.0 };
+        assert!(decide(two_thirds.clone(), tally(1, 2)).accepted);
+        assert!(!decide(two_thirds.clone(), tally(2, 3)).accepted);
+        assert!(!decide(two_thirds, tally(0, 0)).accepted);
+
+        let unanimous = DecisionPolicy::Supermajority { percent: 100.0 };
+        assert!(decide(unanimous.clone(), tally(0, 3)).accepted);
+        assert!(!decide(unanimous, tally(1, 3)).accepted);
+    }
+
+    #[test]
+    fn supermajorities_need_at_least_half() {
+        assert!(DecisionPolicy::Supermajority { percent: 50.0 }
+            .validate()
+            .is_ok());
+        assert!(DecisionPolicy::Supermajority { percent: 40.0 }
+            .validate()
+            .is_err());
+        assert!(DecisionPolicy::Supermajority { percent: 101.0 }
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn only_a_majority_can_tie() {
+        assert!(DecisionPolicy::Majority.is_tie(tally(2, 2)));
+        assert!(!DecisionPolicy::Majority.is_tie(tally(1, 2)));
+        assert!(!DecisionPolicy::Supermajority { percent: 50.0 }.is_tie(tally(2, 2)));
+        assert!(!DecisionPolicy::Lottery.is_tie(tally(2, 2)));
+    }
+
+    #[test]
+    fn a_committed_seed_decides_the_draw() {
+        let seed = 42;
+        let first = decide(DecisionPolicy::Lottery, tally(1, 1));
+        let second = decide(DecisionPolicy::Lottery, tally(1, 1));
+        assert_eq!(first, second);
+
+        let draw = LotteryDraw::draw(0.5, &id(), seed);
+        assert_eq!(first.lottery, Some(draw.clone()));
+        assert_eq!(first.accepted, draw.won());
+        assert!(first.explanation.contains(&format!("seed `{seed}`")));
+        assert!(first.explanation.contains(&LotteryDraw::commitment(seed)));
+    }
+
+    #[test]
+    fn the_commitment_is_the_hash_of_the_seed() {
+        // sha256("42")
+        assert_eq!(
+            LotteryDraw::commitment(42),
+            "73475cb40a568e8da8a045ced110137e159f890ac4da883b6b17dc651b3a8049"
+        );
+    }
+
+    #[test]
+    fn the_roll_changes_with_the_revision() {
+        let pushed = PrId {
+            revision: "0000000000000000000000000000000000000000".to_string(),
+            ..id()
+        };
+        assert_ne!(LotteryDraw::roll(42, &id()), LotteryDraw::roll(42, &pushed));
+        assert!((0.0..1.0).contains(&LotteryDraw::roll(42, &id())));
+    }
+
+    #[test]
+    fn a_lottery_without_votes_is_never_won() {
+        assert!(!decide(DecisionPolicy::Lottery, tally(0, 0)).accepted);
+        assert!(!decide(DecisionPolicy::Lottery, tally(3, 0)).accepted);
+        assert!(decide(DecisionPolicy::Lottery, tally(0, 3)).accepted);
+    }
+
+    /// One signed in vote to deny, and three anonymous votes worth a quarter each to accept
+    fn mostly_anonymous() -> Tally {
+        Tally {
+            left_votes: 1,
+            right_votes: 3,
+            left_weight: 1.0,
+            right_weight: 0.75,
+        }
+    }
+
+    #[test]
+    fn every_policy_weighs_votes() {
+        assert!(!decide(DecisionPolicy::Majority, mostly_anonymous()).accepted);
+
+        // 0.75 of 1.75 is 42.9%
+        let lenient = DecisionPolicy::Supermajority { percent: 50.0 };
+        assert!(!decide(lenient, mostly_anonymous()).accepted);
+
+        let lottery = decide(DecisionPolicy::Lottery, mostly_anonymous());
+        assert!(lottery.explanation.contains("42.9% chance"));
+    }
+
+    #[test]
+    fn ties_are_decided_by_weight() {
+        let tied = Tally {
+            left_votes: 1,
+            right_votes: 2,
+            left_weight: 1.0,
+            right_weight: 1.0,
+        };
+        assert!(DecisionPolicy::Majority.is_tie(tied));
+        assert!(!DecisionPolicy::Majority.is_tie(tally(1, 2)));
+    }
+
+    #[test]
+    fn votes_without_weight_accept_nothing() {
+        let weightless = Tally {
+            left_votes: 0,
+            right_votes: 2,
+            left_weight: 0.0,
+            right_weight: 0.0,
+        };
+        assert!(!decide(DecisionPolicy::Majority, weightless).accepted);
+        let unanimous = DecisionPolicy::Supermajority { percent: 100.0 };
+        assert!(!decide(unanimous, weightless).accepted);
+        assert!(!decide(DecisionPolicy::Lottery, weightless).accepted);
+    }
+}
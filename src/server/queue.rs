@@ -0,0 +1,292 @@
+use serde::Deserialize;
+
+use super::{auth::encode_hex, server::PullRequestInfo, webhook::decode_hex};
+use crate::{ApiError, ApiErrorCode, QueueEntry, QueuePage, QueueTally};
+
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 200;
+
+/// What `/prs` orders the queue by. Ties are broken by the PR id, so every PR has a stable place in the order
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueSort {
+    #[default]
+    Deadline,
+    Created,
+    Votes,
+}
+
+impl QueueSort {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Deadline => "deadline",
+            Self::Created => "created",
+            Self::Votes => "votes",
+        }
+    }
+
+    fn value(self, pr: &PullRequestInfo) -> i64 {
+        match self {
+            Self::Deadline => pr.deadline.timestamp_millis(),
+            Self::Created => pr.creation_time.timestamp_millis(),
+            Self::Votes => (pr.left_votes() + pr.right_votes()) as i64,
+        }
+    }
+}
+
+/// The query parameters of `/prs`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct QueueQuery {
+    pub sort: QueueSort,
+    /// Largest first instead of smallest first
+    pub desc: bool,
+    /// How many PRs to return, up to 200
+    pub limit: Option<usize>,
+    /// The `next_cursor` of the previous page
+    pub cursor: Option<String>,
+    /// Include how many voters accepted, denied and abstained
+    pub tallies: bool,
+}
+
+/// Where a page starts: just after the PR with this sort value and key
+struct Cursor {
+    value: i64,
+    key: String,
+}
+
+impl Cursor {
+    fn encode(&self, sort: QueueSort) -> String {
+        encode_hex(format!("{}:{}:{}", sort.name(), self.value, self.key).as_bytes())
+    }
+
+    /// Cursors only make sense for the order they were made for
+    fn decode(cursor: &str, sort: QueueSort) -> Option<Self> {
+        let cursor = String::from_utf8(decode_hex(cursor)?).ok()?;
+        let mut parts = cursor.splitn(3, ':');
+        parts.next().filter(|&name| name == sort.name())?;
+        Some(Self {
+            value: parts.next()?.parse().ok()?,
+            key: parts.next()?.to_string(),
+        })
+    }
+}
+
+/// How many items to list per page, from the `limit` query parameter
+pub fn page_size(limit: Option<usize>) -> Result<usize, ApiError> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT);
+    if limit == 0 || limit > MAX_LIMIT {
+        return Err(ApiError::new(
+            ApiErrorCode::InvalidRequest,
+            format!("limit must be between 1 and {MAX_LIMIT}"),
+        ));
+    }
+    Ok(limit)
+}
+
+/// One page of the queue in the order `query` asks for
+pub fn page(prs: Vec<PullRequestInfo>, query: &QueueQuery) -> Result<QueuePage, ApiError> {
+    let limit = page_size(query.limit)?;
+    let cursor = match &query.cursor {
+        Some(cursor) => Some(Cursor::decode(cursor, query.sort).ok_or_else(|| {
+            ApiError::new(
+                ApiErrorCode::InvalidRequest,
+                "cursor is not from a page with the same sort",
+            )
+        })?),
+        None => None,
+    };
+
+    let sort = query.sort;
+    let mut keyed: Vec<((i64, String), PullRequestInfo)> = prs
+        .into_iter()
+        .map(|pr| ((sort.value(&pr), pr.pull_request.id().key()), pr))
+        .collect();
+    keyed.sort_by(|(a, _), (b, _)| if query.desc { b.cmp(a) } else { a.cmp(b) });
+    if let Some(cursor) = cursor {
+        let after = (cursor.value, cursor.key);
+        keyed.retain(|(key, _)| {
+            if query.desc {
+                *key < after
+            } else {
+                *key > after
+            }
+        });
+    }
+
+    let more = keyed.len() > limit;
+    keyed.truncate(limit);
+    let last = keyed.last().filter(|_| more);
+    let next_cursor = last.map(|((value, key), _)| {
+        Cursor {
+            value: *value,
+            key: key.clone(),
+        }
+        .encode(sort)
+    });
+    let items = keyed
+        .into_iter()
+        .map(|(_, pr)| entry(pr, query.tallies))
+        .collect();
+    Ok(QueuePage { items, next_cursor })
+}
+
+pub fn tally(pr: &PullRequestInfo) -> QueueTally {
+    QueueTally {
+        accepted: pr.right_votes(),
+        denied: pr.left_votes(),
+        abstained: pr.abstentions(),
+    }
+}
+
+fn entry(pr: PullRequestInfo, tallies: bool) -> QueueEntry {
+    let tally = tallies.then(|| tally(&pr));
+    QueueEntry {
+        id: pr.pull_request.id(),
+        votes: pr.left_votes() + pr.right_votes(),
+        tally,
+        title: pr.pull_request.title,
+        repo_owner: pr.pull_request.repo_owner,
+        repo_name: pr.pull_request.repo_name,
+        author: pr.pull_request.author,
+        creation_time: pr.creation_time,
+        deadline: pr.deadline,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Direction;
+
+    fn now() -> chrono::DateTime<chrono::Utc> {
+        "2025-01-02T12:00:00Z".parse().unwrap()
+    }
+
+    /// PR `number`, queued `number` hours ago, whose vote ends in `hours_left` hours and has `votes` votes
+    fn pr(number: u64, hours_left: i64, votes: usize) -> PullRequestInfo {
+        let mut pr: PullRequestInfo =
+            serde_json::from_str(include_str!("fixtures/queued_pr.json")).unwrap();
+        pr.pull_request.pr_number = number;
+        pr.creation_time = now() - chrono::Duration::hours(number as i64);
+        pr.deadline = now() + chrono::Duration::hours(hours_left);
+        pr.votes = (0..votes)
+            .map(|i| (format!("device:{i}"), Direction::Right))
+            .collect();
+        pr
+    }
+
+    fn queue() -> Vec<PullRequestInfo> {
+        vec![pr(1, 5, 2), pr(2, 1, 0), pr(3, 5, 1), pr(4, 3, 2)]
+    }
+
+    fn numbers(page: &QueuePage) -> Vec<u64> {
+        page.items.iter().map(|entry| entry.id.number).collect()
+    }
+
+    /// Every page of the queue, following the cursors
+    fn pages(query: QueueQuery) -> Vec<Vec<u64>> {
+        let mut query = query;
+        let mut pages = Vec::new();
+        loop {
+            let page = page(queue(), &query).unwrap();
+            pages.push(numbers(&page));
+            match page.next_cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => return pages,
+            }
+        }
+    }
+
+    #[test]
+    fn ties_are_broken_by_the_id() {
+        let query = |sort, desc| QueueQuery {
+            sort,
+            desc,
+            ..QueueQuery::default()
+        };
+        assert_eq!(pages(query(QueueSort::Deadline, false)), [vec![2, 4, 1, 3]]);
+        assert_eq!(pages(query(QueueSort::Deadline, true)), [vec![3, 1, 4, 2]]);
+        assert_eq!(pages(query(QueueSort::Created, false)), [vec![4, 3, 2, 1]]);
+        assert_eq!(pages(query(QueueSort::Votes, false)), [vec![2, 3, 1, 4]]);
+    }
+
+    #[test]
+    fn cursors_continue_where_the_last_page_ended() {
+        for desc in [false, true] {
+            let query = QueueQuery {
+                sort: QueueSort::Votes,
+                desc,
+                limit: Some(1),
+                ..QueueQuery::default()
+            };
+            let all = QueueQuery {
+                limit: None,
+                ..query.clone()
+            };
+            let paged: Vec<u64> = pages(query).into_iter().flatten().collect();
+            assert_eq!(vec![paged], pages(all));
+        }
+    }
+
+    #[test]
+    fn a_cursor_only_works_for_its_own_sort() {
+        let first = page(
+            queue(),
+            &QueueQuery {
+                limit: Some(2),
+                ..QueueQuery::default()
+            },
+        )
+        .unwrap();
+        let cursor = first.next_cursor.unwrap();
+        let other_sort = QueueQuery {
+            sort: QueueSort::Created,
+            cursor: Some(cursor),
+            ..QueueQuery::default()
+        };
+        assert_eq!(
+            page(queue(), &other_sort).unwrap_err().code,
+            ApiErrorCode::InvalidRequest
+        );
+        let garbage = QueueQuery {
+            cursor: Some("zz".to_string()),
+            ..QueueQuery::default()
+        };
+        assert!(page(queue(), &garbage).is_err());
+    }
+
+    #[test]
+    fn the_limit_is_checked() {
+        for limit in [0, MAX_LIMIT + 1] {
+            let query = QueueQuery {
+                limit: Some(limit),
+                ..QueueQuery::default()
+            };
+            assert!(page(queue(), &query).is_err());
+        }
+    }
+
+    #[test]
+    fn tallies_are_only_included_when_asked_for() {
+        let mut queued = pr(1, 5, 1);
+        queued.votes.insert("github:1".to_string(), Direction::Left);
+        queued.votes.insert("github:2".to_string(), Direction::Skip);
+        let listed = page(vec![queued.clone()], &QueueQuery::default()).unwrap();
+        assert_eq!(listed.items[0].tally, None);
+        assert_eq!(listed.items[0].votes, 2);
+
+        let query = QueueQuery {
+            tallies: true,
+            ..QueueQuery::default()
+        };
+        assert_eq!(
+            page(vec![queued], &query).unwrap().items[0].tally,
+            Some(QueueTally {
+                accepted: 1,
+                denied: 1,
+                abstained: 1,
+            })
+        );
+    }
+}
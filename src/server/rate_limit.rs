@@ -0,0 +1,287 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    http::{header::RETRY_AFTER, HeaderMap, HeaderName},
+    response::{IntoResponse, Response},
+};
+
+use crate::{ApiError, ApiErrorCode};
+
+/// Buckets that are full again are dropped once there are this many, so idle clients don't pile up
+const PRUNE_AFTER_BUCKETS: usize = 10_000;
+
+/// How many requests a client can make in a burst, and how quickly it earns them back
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    pub burst: f64,
+    pub per_minute: f64,
+}
+
+impl Limit {
+    /// Read a limit written as `<burst>,<per minute>` from the environment variable `var`
+    fn from_env(var: &str, default: Limit) -> Self {
+        let Ok(value) = std::env::var(var) else {
+            return default;
+        };
+        let parsed = value.split_once(',').and_then(|(burst, per_minute)| {
+            Some(Self {
+                burst: burst.trim().parse().ok()?,
+                per_minute: per_minute.trim().parse().ok()?,
+            })
+        });
+        match parsed {
+            Some(limit) if limit.burst >= 1.0 && limit.per_minute > 0.0 => limit,
+            _ => panic!(
+                "{var} must be `<burst>,<per minute>` with a burst of at least 1, found {value}"
+            ),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// A token bucket for every client of an endpoint
+#[derive(Clone)]
+pub struct RateLimiter {
+    limit: Limit,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: Limit) -> Self {
+        Self {
+            limit,
+            buckets: Arc::default(),
+        }
+    }
+
+    /// Take a token from the bucket of `key`. If it is empty, returns how long until there is one again
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let per_second = self.limit.per_minute / 60.0;
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_AFTER_BUCKETS {
+            let limit = self.limit;
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * per_second
+                    < limit.burst
+            });
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.limit.burst,
+            updated: now,
+        });
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() * per_second;
+        bucket.tokens = (bucket.tokens + refilled).min(self.limit.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+        }
+    }
+}
+
+/// The limits on the endpoints voters hit. Each one limits every IP and every voter separately
+#[derive(Clone)]
+pub struct RateLimits {
+    pub pr: RateLimiter,
+    pub vote: RateLimiter,
+    /// How many device tokens each IP is handed. Every token is another anonymous vote, so a script that drops
+    /// its cookies mustn't get a new one with every `/pr`
+    pub device: RateLimiter,
+    /// `/history`, `/prs`, `/events` and `/leaderboards`, so browsing the queue doesn't use up the budget for
+    /// voting
+    pub browse: RateLimiter,
+    /// The header the proxy in front of the server puts the client IP in. Without one, the IP is the peer's
+    client_ip_header: Option<HeaderName>,
+}
+
+/// The limits without a proxy in front of the server
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            pr: RateLimiter::new(Limit {
+                burst: 30.0,
+                per_minute: 60.0,
+            }),
+            vote: RateLimiter::new(Limit {
+                burst: 20.0,
+                per_minute: 30.0,
+            }),
+            device: RateLimiter::new(Limit {
+                burst: 10.0,
+                per_minute: 0.1,
+            }),
+            browse: RateLimiter::new(Limit {
+                burst: 30.0,
+                per_minute: 60.0,
+            }),
+            client_ip_header: None,
+        }
+    }
+}
+
+impl RateLimits {
+    /// Read the limits from `GITLUCKY_PR_LIMIT`, `GITLUCKY_VOTE_LIMIT`, `GITLUCKY_DEVICE_LIMIT` and
+    /// `GITLUCKY_BROWSE_LIMIT`, and where the proxy in front of the server puts the client IP from
+    /// `GITLUCKY_CLIENT_IP_HEADER`. Unset limits keep their default
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let client_ip_header = std::env::var("GITLUCKY_CLIENT_IP_HEADER").ok().map(|name| {
+            HeaderName::try_from(name.as_str()).unwrap_or_else(|_| {
+                panic!("GITLUCKY_CLIENT_IP_HEADER must be a header name, found {name}")
+            })
+        });
+        Self {
+            pr: RateLimiter::new(Limit::from_env("GITLUCKY_PR_LIMIT", defaults.pr.limit)),
+            vote: RateLimiter::new(Limit::from_env("GITLUCKY_VOTE_LIMIT", defaults.vote.limit)),
+            device: RateLimiter::new(Limit::from_env(
+                "GITLUCKY_DEVICE_LIMIT",
+                defaults.device.limit,
+            )),
+            browse: RateLimiter::new(Limit::from_env(
+                "GITLUCKY_BROWSE_LIMIT",
+                defaults.browse.limit,
+            )),
+            client_ip_header,
+        }
+    }
+
+    /// The IP a request came from. Behind a proxy that is the last entry of its header: a proxy appends the
+    /// address it saw to `X-Forwarded-For`, and anything before it was sent by the client
+    pub fn client_ip(&self, addr: SocketAddr, headers: &HeaderMap) -> IpAddr {
+        let forwarded = self.client_ip_header.as_ref().and_then(|name| {
+            let value = headers.get_all(name).iter().next_back()?.to_str().ok()?;
+            value.rsplit(',').next()?.trim().parse().ok()
+        });
+        forwarded.unwrap_or(addr.ip())
+    }
+}
+
+/// The bucket of a client was empty
+#[derive(Debug)]
+pub struct RateLimited {
+    retry_after: Duration,
+}
+
+/// A 429 response telling the client when to try again
+impl IntoResponse for RateLimited {
+    fn into_response(self) -> Response {
+        let seconds = self.retry_after.as_secs_f64().ceil().max(1.0) as u64;
+        let error = ApiError::new(
+            ApiErrorCode::RateLimited,
+            format!("Too many requests, try again in {seconds} seconds"),
+        );
+        ([(RETRY_AFTER, seconds.to_string())], error).into_response()
+    }
+}
+
+/// Check the bucket of the client IP and, if known, the voter
+pub fn check(limiter: &RateLimiter, ip: IpAddr, voter: Option<&str>) -> Result<(), RateLimited> {
+    let result = limiter
+        .check(&format!("ip:{ip}"))
+        .and_then(|()| voter.map_or(Ok(()), |voter| limiter.check(voter)));
+    result.map_err(|retry_after| {
+        println!("Rate limiting {} ({:?})", ip, voter);
+        RateLimited { retry_after }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(client_ip_header: Option<&str>) -> RateLimits {
+        RateLimits {
+            client_ip_header: client_ip_header.map(|name| HeaderName::try_from(name).unwrap()),
+            ..RateLimits::default()
+        }
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    const PEER: SocketAddr = SocketAddr::new(IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1)), 443);
+
+    #[test]
+    fn takes_the_address_the_proxy_added() {
+        let limits = limits(Some("X-Forwarded-For"));
+        let spoofed = headers(&[("x-forwarded-for", "1.2.3.4, 203.0.113.7")]);
+        assert_eq!(
+            limits.client_ip(PEER, &spoofed),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+
+        // A proxy may append its own line instead of extending the client's
+        let appended = headers(&[
+            ("x-forwarded-for", "1.2.3.4"),
+            ("x-forwarded-for", "203.0.113.7"),
+        ]);
+        assert_eq!(
+            limits.client_ip(PEER, &appended),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn takes_the_address_fly_sets() {
+        let limits = limits(Some("Fly-Client-IP"));
+        let request = headers(&[
+            ("fly-client-ip", "2001:db8::1"),
+            ("x-forwarded-for", "1.2.3.4"),
+        ]);
+        assert_eq!(
+            limits.client_ip(PEER, &request),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn ignores_the_headers_without_a_proxy() {
+        let request = headers(&[("x-forwarded-for", "1.2.3.4")]);
+        assert_eq!(limits(None).client_ip(PEER, &request), PEER.ip());
+
+        // A header that isn't an address is ignored too
+        let garbage = headers(&[("fly-client-ip", "localhost")]);
+        assert_eq!(
+            limits(Some("Fly-Client-IP")).client_ip(PEER, &garbage),
+            PEER.ip()
+        );
+    }
+
+    #[test]
+    fn empty_buckets_refuse_until_they_refill() {
+        let limiter = RateLimiter::new(Limit {
+            burst: 2.0,
+            per_minute: 60.0,
+        });
+        let ip = PEER.ip();
+        assert!(check(&limiter, ip, None).is_ok());
+        assert!(check(&limiter, ip, Some("github:1")).is_ok());
+        let limited = check(&limiter, ip, None).unwrap_err();
+        assert!(limited.retry_after <= Duration::from_secs(1));
+
+        let response = limited.into_response();
+        assert_eq!(response.status(), axum::http::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "1");
+
+        // Other clients have their own buckets
+        assert!(check(&limiter, "10.0.0.2".parse().unwrap(), None).is_ok());
+    }
+}
@@ -0,0 +1,322 @@
+use serde::{Deserialize, Serialize};
+
+use super::{policy::DecisionPolicy, server::OnSynchronize};
+
+/// The file a repo commits on its default branch to configure Git Lucky
+pub const CONFIG_PATH: &str = ".gitlucky.toml";
+
+/// The longest a vote can stay open. Longer is most likely a typo, and big enough values overflow the deadline
+pub const MAX_VOTING_MINUTES: u64 = 60 * 24 * 365;
+
+/// The rules a repo votes on its PRs with. Every field is optional in `.gitlucky.toml`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RepoConfig {
+    /// How long the vote on a PR stays open
+    pub voting_minutes: u64,
+    /// The minimum number of votes for the vote to count
+    pub quorum: usize,
+    /// How many times the vote is extended when it ends without reaching the quorum
+    pub max_extensions: u32,
+    /// How long each extension lasts. Defaults to `voting_minutes`
+    pub extension_minutes: Option<u64>,
+    /// What happens when the vote still hasn't reached the quorum after every extension
+    pub quorum_fallback: QuorumFallback,
+    /// How the votes decide whether the PR is merged
+    pub policy: DecisionPolicy,
+    /// What a vote from a voter who didn't sign in is worth, in every policy and toward the quorum. Signed in
+    /// votes are worth 1
+    pub anonymous_weight: f64,
+    /// What happens when the vote ends without a single vote
+    pub on_no_votes: UndecidedOutcome,
+    /// What happens when the vote ends in a tie
+    pub on_tie: UndecidedOutcome,
+    pub merge_method: MergeMethod,
+    /// Only PRs into these branches are voted on. Empty means every branch
+    pub target_branches: Vec<String>,
+    /// PRs that touch any of these paths are left to the maintainers. Supports `*` and `**`
+    pub excluded_paths: Vec<String>,
+    /// What happens to the votes on a PR when new commits are pushed to it
+    pub on_synchronize: OnSynchronize,
+}
+
+impl Default for RepoConfig {
+    fn default() -> Self {
+        Self {
+            voting_minutes: 60 * 24,
+            quorum: 0,
+            max_extensions: 0,
+            extension_minutes: None,
+            quorum_fallback: QuorumFallback::Close,
+            policy: DecisionPolicy::default(),
+            anonymous_weight: 0.5,
+            on_no_votes: UndecidedOutcome::Deny,
+            on_tie: UndecidedOutcome::Deny,
+            merge_method: MergeMethod::Merge,
+            target_branches: Vec::new(),
+            excluded_paths: Vec::new(),
+            on_synchronize: OnSynchronize::default(),
+        }
+    }
+}
+
+/// Why the config of a repo couldn't be loaded
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    /// The repo has a config, but it's not valid. Holds every problem found
+    Invalid(Vec<String>),
+    /// The config couldn't be fetched, so whether it's valid is unknown
+    Unavailable(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuorumFallback {
+    Merge,
+    Close,
+    /// Stop voting and leave the PR for the maintainers
+    LeaveOpen,
+}
+
+/// What happens to a PR when the votes don't decide it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UndecidedOutcome {
+    Accept,
+    Deny,
+    /// Extend the vote, as long as `max_extensions` allows. After that the PR is left for the maintainers
+    Extend,
+    /// Stop voting and leave the PR for the maintainers
+    Maintainer,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeMethod {
+    #[default]
+    Merge,
+    Squash,
+    Rebase,
+}
+
+impl RepoConfig {
+    /// Parse and validate the contents of `.gitlucky.toml`. Returns every problem found, not just the first
+    pub fn parse(source: &str) -> Result<Self, Vec<String>> {
+        let config: Self = toml::from_str(source).map_err(|e| vec![e.to_string()])?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        if !(1..=MAX_VOTING_MINUTES).contains(&self.voting_minutes) {
+            errors.push(format!(
+                "`voting_minutes` must be between 1 and {MAX_VOTING_MINUTES} (a year), found {}",
+                self.voting_minutes
+            ));
+        }
+        if let Some(extension_minutes) = self.extension_minutes {
+            if !(1..=MAX_VOTING_MINUTES).contains(&extension_minutes) {
+                errors.push(format!(
+                    "`extension_minutes` must be between 1 and {MAX_VOTING_MINUTES} (a year), found {}",
+                    extension_minutes
+                ));
+            }
+        }
+        if let Err(e) = self.policy.validate() {
+            errors.push(e);
+        }
+        if !(0.0..=1.0).contains(&self.anonymous_weight) {
+            errors.push(format!(
+                "`anonymous_weight` must be between 0 and 1, found {}",
+                self.anonymous_weight
+            ));
+        }
+        if self.target_branches.iter().any(|branch| branch.is_empty()) {
+            errors.push("`target_branches` can't contain an empty branch name".to_string());
+        }
+        if self.excluded_paths.iter().any(|path| path.is_empty()) {
+            errors.push("`excluded_paths` can't contain an empty pattern".to_string());
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn voting_duration(&self) -> chrono::Duration {
+        chrono::Duration::minutes(self.voting_minutes as i64)
+    }
+
+    pub fn extension_duration(&self) -> chrono::Duration {
+        chrono::Duration::minutes(self.extension_minutes.unwrap_or(self.voting_minutes) as i64)
+    }
+
+    pub fn targets_branch(&self, branch: &str) -> bool {
+        self.target_branches.is_empty() || self.target_branches.iter().any(|b| b == branch)
+    }
+
+    /// The first changed file in `diff` that matches one of the excluded paths
+    pub fn excluded_file<'a>(&self, diff: &'a str) -> Option<&'a str> {
+        changed_files(diff).find(|file| {
+            self.excluded_paths
+                .iter()
+                .any(|pattern| glob_matches(pattern, file))
+        })
+    }
+}
+
+/// The paths of every file a git diff touches. A renamed file touches both its old and new path
+pub fn changed_files(diff: &str) -> impl Iterator<Item = &str> {
+    // Every file in the diff starts with `diff --git a/<old path> b/<new path>`
+    diff.lines()
+        .filter_map(|line| line.strip_prefix("diff --git a/"))
+        .flat_map(|paths| {
+            // Unless the file was renamed both paths are the same, which also works for paths containing ` b/`
+            let half = paths.len().saturating_sub(3) / 2;
+            let (old, new) = match (paths.get(..half), paths.get(half..)) {
+                (Some(old), Some(rest)) if rest.strip_prefix(" b/") == Some(old) => (old, old),
+                _ => paths.split_once(" b/").unwrap_or((paths, paths)),
+            };
+            std::iter::once(old).chain((old != new).then_some(new))
+        })
+}
+
+/// Only the `diff --git` line of every file in `diff`, which is enough for [`changed_files`]
+pub fn diff_headers(diff: &str) -> String {
+    diff.lines()
+        .filter(|line| line.starts_with("diff --git "))
+        .flat_map(|line| [line, "\n"])
+        .collect()
+}
+
+/// Match a path against a pattern where `*` matches within a path segment and `**` matches across them
+fn glob_matches(pattern: &str, path: &str) -> bool {
+    fn matches(pattern: &[u8], path: &[u8]) -> bool {
+        match pattern {
+            [] => path.is_empty(),
+            // `**/` matches whole segments, so the rest of the pattern starts at the beginning of one
+            [b'*', b'*', b'/', rest @ ..] => (0..=path.len())
+                .filter(|&i| i == 0 || path[i - 1] == b'/')
+                .any(|i| matches(rest, &path[i..])),
+            [b'*', b'*', rest @ ..] => (0..=path.len()).any(|i| matches(rest, &path[i..])),
+            [b'*', rest @ ..] => (0..=path.len())
+                .take_while(|&i| i == 0 || path[i - 1] != b'/')
+                .any(|i| matches(rest, &path[i..])),
+            [c, rest @ ..] => path.first() == Some(c) && matches(rest, &path[1..]),
+        }
+    }
+    // A pattern for a directory excludes everything inside it
+    let pattern = pattern.trim_end_matches('/');
+    matches(pattern.as_bytes(), path.as_bytes())
+        || matches(format!("{pattern}/**").as_bytes(), path.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn double_star_matches_whole_segments() {
+        assert!(glob_matches("**/foo.rs", "foo.rs"));
+        assert!(glob_matches("**/foo.rs", "src/foo.rs"));
+        assert!(glob_matches("**/foo.rs", "src/server/foo.rs"));
+        assert!(!glob_matches("**/foo.rs", "src/myfoo.rs"));
+        assert!(!glob_matches("**/foo.rs", "myfoo.rs"));
+        assert!(glob_matches("src/**/*.rs", "src/main.rs"));
+        assert!(glob_matches("src/**/*.rs", "src/server/store/json.rs"));
+        assert!(!glob_matches("src/**/*.rs", "srcs/main.rs"));
+        assert!(glob_matches("src/**", "src/server/store/json.rs"));
+    }
+
+    #[test]
+    fn single_star_stays_within_a_segment() {
+        assert!(glob_matches("*.lock", "Cargo.lock"));
+        assert!(!glob_matches("*.lock", "vendor/Cargo.lock"));
+        assert!(glob_matches("src/*.rs", "src/main.rs"));
+        assert!(!glob_matches("src/*.rs", "src/server/mod.rs"));
+    }
+
+    #[test]
+    fn directory_patterns_match_everything_inside() {
+        assert!(glob_matches(".github", ".github/workflows/ci.yml"));
+        assert!(glob_matches(".github/", ".github/workflows/ci.yml"));
+        assert!(!glob_matches(".github", ".githubusercontent"));
+    }
+
+    #[test]
+    fn reads_the_changed_files_from_the_diff_headers() {
+        let diff = "diff --git a/src/main.rs b/src/main.rs\n\
+            --- a/src/main.rs\n\
+            +++ b/src/main.rs\n\
+            @@ -1,2 +1 @@\n\
+            --- a/not/a/header.rs\n\
+            -fn main() {}\n\
+            diff --git a/old name.txt b/new name.txt\n\
+            similarity index 100%\n\
+            diff --git a/docs/a b/c.md b/docs/a b/c.md\n\
+            deleted file mode 100644\n";
+        assert_eq!(
+            changed_files(diff).collect::<Vec<_>>(),
+            [
+                "src/main.rs",
+                "old name.txt",
+                "new name.txt",
+                "docs/a b/c.md"
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_excluded_files_in_a_diff() {
+        let config = RepoConfig::parse(r#"excluded_paths = ["**/Cargo.toml", ".github"]"#).unwrap();
+        let diff = "diff --git a/src/main.rs b/src/main.rs\n\
+            diff --git a/crates/app/Cargo.toml b/crates/app/Cargo.toml\n";
+        assert_eq!(config.excluded_file(diff), Some("crates/app/Cargo.toml"));
+        let diff = "diff --git a/src/MyCargo.toml b/src/MyCargo.toml\n";
+        assert_eq!(config.excluded_file(diff), None);
+        // Moving a file out of an excluded path touches it too
+        let diff = "diff --git a/.github/ci.yml b/ci.yml\n";
+        assert_eq!(config.excluded_file(diff), Some(".github/ci.yml"));
+    }
+
+    #[test]
+    fn reports_every_invalid_setting() {
+        let errors = RepoConfig::parse(
+            "voting_minutes = 0\npolicy = { kind = \"supermajority\", percent = 40 }",
+        )
+        .unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(
+            RepoConfig::parse("voting_minutes = 1000000")
+                .unwrap_err()
+                .len(),
+            1
+        );
+        assert_eq!(
+            RepoConfig::parse("extension_minutes = 0")
+                .unwrap_err()
+                .len(),
+            1
+        );
+        assert!(RepoConfig::parse(&format!("voting_minutes = {MAX_VOTING_MINUTES}")).is_ok());
+        assert!(RepoConfig::parse("quorum = 3\nmax_extensions = 2").is_ok());
+        assert!(RepoConfig::parse("unknown_setting = 1").is_err());
+        assert_eq!(RepoConfig::parse(""), Ok(RepoConfig::default()));
+    }
+
+    #[test]
+    fn reads_the_decision_policy() {
+        assert_eq!(RepoConfig::default().policy, DecisionPolicy::Majority);
+        let config =
+            RepoConfig::parse("policy = { kind = \"supermajority\", percent = 66 }").unwrap();
+        assert_eq!(
+            config.policy,
+            DecisionPolicy::Supermajority { percent: 66.0 }
+        );
+        let config = RepoConfig::parse("policy = { kind = \"lottery\" }").unwrap();
+        assert_eq!(config.policy, DecisionPolicy::Lottery);
+        assert!(RepoConfig::parse("policy = { kind = \"weighted\" }").is_err());
+    }
+}
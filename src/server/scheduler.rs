@@ -0,0 +1,318 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use tokio::task::AbortHandle;
+
+/// The source of time for the scheduler. Swapping this out lets tests control when deadlines pass
+pub trait Clock: Send + Sync + 'static {
+    fn now(&self) -> DateTime<Utc>;
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+}
+
+/// The wall clock, backed by tokio's timer
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// Runs one task per key once its deadline passes. Scheduling a key again replaces the old task
+#[derive(Clone)]
+pub struct Scheduler {
+    clock: Arc<dyn Clock>,
+    tasks: Arc<Mutex<HashMap<String, ScheduledTask>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+struct ScheduledTask {
+    /// Tells a task apart from the one that replaced it under the same key
+    id: u64,
+    handle: AbortHandle,
+}
+
+impl Scheduler {
+    pub fn new(clock: impl Clock) -> Self {
+        Self {
+            clock: Arc::new(clock),
+            tasks: Arc::new(Mutex::new(HashMap::new())),
+            next_id: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
+    }
+
+    /// Run `task` at `deadline`. If the deadline already passed, the task runs right away
+    pub fn schedule<F, Fut>(&self, key: String, deadline: DateTime<Utc>, task: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        // A negative duration means the deadline is already behind us
+        let delay = (deadline - self.clock.now())
+            .to_std()
+            .unwrap_or(Duration::ZERO);
+        let sleep = self.clock.sleep(delay);
+        let scheduler = self.clone();
+        let task_key = key.clone();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+        // Hold the lock while spawning so the task can't remove itself before it is inserted
+        let mut all_tasks = self.tasks.lock().unwrap();
+        let handle = tokio::spawn(async move {
+            sleep.await;
+            scheduler.finish(&task_key, id);
+            task().await;
+        });
+        let scheduled = ScheduledTask {
+            id,
+            handle: handle.abort_handle(),
+        };
+        if let Some(old) = all_tasks.insert(key, scheduled) {
+            old.handle.abort();
+        }
+    }
+
+    /// Cancel the task for `key`. Returns false if nothing was scheduled
+    pub fn cancel(&self, key: &str) -> bool {
+        match self.tasks.lock().unwrap().remove(key) {
+            Some(task) => {
+                task.handle.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Forget the task `id` under `key` once it starts running. A task that woke up just as it was
+    /// replaced must leave its replacement alone
+    fn finish(&self, key: &str, id: u64) {
+        let mut tasks = self.tasks.lock().unwrap();
+        if tasks.get(key).is_some_and(|task| task.id == id) {
+            tasks.remove(key);
+        }
+    }
+}
+
+/// A clock for tests that only moves when the test says so
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+    use tokio::sync::oneshot;
+
+    /// Sleeps waiting for the mock clock, with the time each one wakes up at
+    type Sleepers = Vec<(DateTime<Utc>, oneshot::Sender<()>)>;
+
+    /// A clock that only moves when the test advances it
+    #[derive(Clone)]
+    pub struct MockClock {
+        now: Arc<Mutex<DateTime<Utc>>>,
+        sleepers: Arc<Mutex<Sleepers>>,
+    }
+
+    impl MockClock {
+        pub fn new() -> Self {
+            Self {
+                now: Arc::new(Mutex::new(
+                    DateTime::parse_from_rfc3339("2025-01-01T12:00:00Z")
+                        .unwrap()
+                        .to_utc(),
+                )),
+                sleepers: Arc::new(Mutex::new(Vec::new())),
+            }
+        }
+
+        /// Move time forward and wake everything that was sleeping until then
+        pub async fn advance(&self, minutes: i64) {
+            let now = {
+                let mut now = self.now.lock().unwrap();
+                *now += chrono::Duration::minutes(minutes);
+                *now
+            };
+            let due: Sleepers = {
+                let mut sleepers = self.sleepers.lock().unwrap();
+                let (due, waiting) = sleepers.drain(..).partition(|(wake, _)| *wake <= now);
+                *sleepers = waiting;
+                due
+            };
+            for (_, sender) in due {
+                let _ = sender.send(());
+            }
+            settle().await;
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> DateTime<Utc> {
+            *self.now.lock().unwrap()
+        }
+
+        fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+            if duration.is_zero() {
+                return Box::pin(std::future::ready(()));
+            }
+            let wake = self.now() + chrono::Duration::from_std(duration).unwrap();
+            let (sender, receiver) = oneshot::channel();
+            self.sleepers.lock().unwrap().push((wake, sender));
+            Box::pin(async move {
+                let _ = receiver.await;
+            })
+        }
+    }
+
+    /// Let every spawned task run until it blocks
+    pub async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{settle, MockClock};
+    use super::*;
+    use tokio::sync::mpsc;
+
+    /// Schedule a task that reports `name` when it runs
+    fn schedule(
+        scheduler: &Scheduler,
+        deadline: DateTime<Utc>,
+        name: &'static str,
+        ran: &mpsc::UnboundedSender<&'static str>,
+    ) {
+        let ran = ran.clone();
+        scheduler.schedule("pr".to_string(), deadline, move || async move {
+            ran.send(name).unwrap();
+        });
+    }
+
+    #[tokio::test]
+    async fn runs_an_overdue_task_right_away() {
+        let clock = MockClock::new();
+        let scheduler = Scheduler::new(clock.clone());
+        let (ran, mut runs) = mpsc::unbounded_channel();
+
+        schedule(
+            &scheduler,
+            clock.now() - chrono::Duration::hours(3),
+            "overdue",
+            &ran,
+        );
+        settle().await;
+        assert_eq!(runs.try_recv(), Ok("overdue"));
+        assert!(scheduler.tasks.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn runs_a_task_once_its_deadline_passes() {
+        let clock = MockClock::new();
+        let scheduler = Scheduler::new(clock.clone());
+        let (ran, mut runs) = mpsc::unbounded_channel();
+
+        schedule(
+            &scheduler,
+            clock.now() + chrono::Duration::minutes(10),
+            "due",
+            &ran,
+        );
+        clock.advance(9).await;
+        assert!(runs.try_recv().is_err());
+        clock.advance(1).await;
+        assert_eq!(runs.try_recv(), Ok("due"));
+    }
+
+    #[tokio::test]
+    async fn cancelled_tasks_never_run() {
+        let clock = MockClock::new();
+        let scheduler = Scheduler::new(clock.clone());
+        let (ran, mut runs) = mpsc::unbounded_channel();
+
+        schedule(
+            &scheduler,
+            clock.now() + chrono::Duration::minutes(10),
+            "cancelled",
+            &ran,
+        );
+        assert!(scheduler.cancel("pr"));
+        assert!(!scheduler.cancel("pr"));
+        clock.advance(60).await;
+        assert!(runs.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn rescheduling_replaces_the_old_task() {
+        let clock = MockClock::new();
+        let scheduler = Scheduler::new(clock.clone());
+        let (ran, mut runs) = mpsc::unbounded_channel();
+
+        schedule(
+            &scheduler,
+            clock.now() + chrono::Duration::minutes(10),
+            "old",
+            &ran,
+        );
+        schedule(
+            &scheduler,
+            clock.now() + chrono::Duration::minutes(20),
+            "new",
+            &ran,
+        );
+        clock.advance(10).await;
+        assert!(runs.try_recv().is_err());
+        clock.advance(10).await;
+        assert_eq!(runs.try_recv(), Ok("new"));
+        assert!(runs.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn a_replaced_task_leaves_its_replacement_scheduled() {
+        let clock = MockClock::new();
+        let scheduler = Scheduler::new(clock.clone());
+        let (ran, _runs) = mpsc::unbounded_channel();
+
+        schedule(
+            &scheduler,
+            clock.now() + chrono::Duration::minutes(10),
+            "old",
+            &ran,
+        );
+        let old = scheduler.tasks.lock().unwrap()["pr"].id;
+        schedule(
+            &scheduler,
+            clock.now() + chrono::Duration::minutes(20),
+            "new",
+            &ran,
+        );
+        let new = scheduler.tasks.lock().unwrap()["pr"].id;
+        // The old task woke up before it could be aborted
+        scheduler.finish("pr", old);
+        assert_eq!(
+            scheduler
+                .tasks
+                .lock()
+                .unwrap()
+                .get("pr")
+                .map(|task| task.id),
+            Some(new)
+        );
+    }
+}
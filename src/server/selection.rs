@@ -0,0 +1,367 @@
+use rand::Rng;
+
+use super::{repo_config::changed_files, server::PullRequestInfo};
+use crate::{PrFilter, PullRequest};
+
+/// How much less likely a PR the voter already voted on is to be picked by [`SelectionStrategy::Weighted`]
+const SEEN_WEIGHT: f64 = 0.1;
+/// How close to its deadline a PR has to get, in hours, before it is noticeably more likely to be picked. A PR at
+/// its deadline is twice as likely as one with all the time in the world
+const URGENT_HOURS: f64 = 24.0;
+
+/// How `/pr` picks the next PR to show a voter
+///
+/// Every strategy leaves out PRs the voter skipped. All but `Weighted` also only pick PRs the voter hasn't
+/// voted on yet, unless they voted on everything.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SelectionStrategy {
+    /// Every PR is equally likely
+    Uniform,
+    /// The PR with the fewest votes
+    FewestVotesFirst,
+    /// The PR whose vote ends first
+    DeadlineFirst,
+    /// Any PR, but ones with few votes, a close deadline and that the voter hasn't seen are more likely
+    #[default]
+    Weighted,
+}
+
+impl SelectionStrategy {
+    /// Read the strategy from `GITLUCKY_SELECTION`
+    pub fn from_env() -> Self {
+        match std::env::var("GITLUCKY_SELECTION").as_deref() {
+            Ok("uniform") => Self::Uniform,
+            Ok("fewest_votes_first") => Self::FewestVotesFirst,
+            Ok("deadline_first") => Self::DeadlineFirst,
+            Ok("weighted") | Err(_) => Self::Weighted,
+            Ok(other) => panic!("Unknown GITLUCKY_SELECTION strategy: {other}"),
+        }
+    }
+
+    /// Pick a PR from the queue for `voter`. Ties are broken at random
+    pub fn select<'a>(
+        self,
+        prs: &'a [PullRequestInfo],
+        voter: Option<&str>,
+        now: chrono::DateTime<chrono::Utc>,
+        rng: &mut impl Rng,
+    ) -> Option<&'a PullRequestInfo> {
+        let seen = |pr: &PullRequestInfo| voter.is_some_and(|voter| pr.votes.contains_key(voter));
+        let candidates: Vec<&PullRequestInfo> = prs
+            .iter()
+            .filter(|pr| voter.is_none_or(|voter| !pr.skipped_by(voter)))
+            .collect();
+        let unseen: Vec<&PullRequestInfo> =
+            candidates.iter().copied().filter(|&pr| !seen(pr)).collect();
+        let preferred = if unseen.is_empty() {
+            &candidates
+        } else {
+            &unseen
+        };
+
+        match self {
+            Self::Uniform => pick_uniform(preferred, rng),
+            Self::FewestVotesFirst => {
+                let fewest = preferred.iter().map(|pr| cast_votes(pr)).min()?;
+                let tied: Vec<_> = preferred
+                    .iter()
+                    .copied()
+                    .filter(|&pr| cast_votes(pr) == fewest)
+                    .collect();
+                pick_uniform(&tied, rng)
+            }
+            Self::DeadlineFirst => {
+                let first = preferred.iter().map(|pr| pr.deadline).min()?;
+                let tied: Vec<_> = preferred
+                    .iter()
+                    .copied()
+                    .filter(|pr| pr.deadline == first)
+                    .collect();
+                pick_uniform(&tied, rng)
+            }
+            Self::Weighted => {
+                let weights: Vec<f64> = candidates
+                    .iter()
+                    .map(|&pr| weight(pr, seen(pr), now))
+                    .collect();
+                let total: f64 = weights.iter().sum();
+                if candidates.is_empty() || total <= 0.0 {
+                    return None;
+                }
+                let mut target = rng.random_range(0.0..total);
+                for (pr, weight) in candidates.iter().zip(&weights) {
+                    if target < *weight {
+                        return Some(*pr);
+                    }
+                    target -= weight;
+                }
+                // Rounding can leave a sliver past the last PR
+                candidates.last().copied()
+            }
+        }
+    }
+}
+
+impl PrFilter {
+    /// Whether `pr` passes every part of the filter that is set. Names and extensions ignore case
+    pub fn matches(&self, pr: &PullRequest) -> bool {
+        let same = |filter: &Option<String>, value: &str| {
+            filter
+                .as_deref()
+                .is_none_or(|filter| filter.eq_ignore_ascii_case(value))
+        };
+        let within = |value: usize, min: Option<usize>, max: Option<usize>| {
+            min.is_none_or(|min| value >= min) && max.is_none_or(|max| value <= max)
+        };
+        same(&self.repo_owner, &pr.repo_owner)
+            && same(&self.repo_name, &pr.repo_name)
+            && within(pr.additions, self.min_additions, self.max_additions)
+            && within(pr.deletions, self.min_deletions, self.max_deletions)
+            && self.matches_extensions(&pr.diff)
+    }
+
+    fn matches_extensions(&self, diff: &str) -> bool {
+        let wanted: Vec<&str> = self
+            .extensions
+            .iter()
+            .flat_map(|extensions| extensions.split(','))
+            .map(|extension| extension.trim().trim_start_matches('.'))
+            .filter(|extension| !extension.is_empty())
+            .collect();
+        wanted.is_empty()
+            || changed_files(diff)
+                .filter_map(|path| path.rsplit('/').next()?.rsplit_once('.'))
+                .any(|(_, extension)| {
+                    wanted
+                        .iter()
+                        .any(|wanted| wanted.eq_ignore_ascii_case(extension))
+                })
+    }
+}
+
+/// Votes that count toward the outcome
+fn cast_votes(pr: &PullRequestInfo) -> usize {
+    pr.left_votes() + pr.right_votes()
+}
+
+fn pick_uniform<'a>(
+    prs: &[&'a PullRequestInfo],
+    rng: &mut impl Rng,
+) -> Option<&'a PullRequestInfo> {
+    if prs.is_empty() {
+        return None;
+    }
+    Some(prs[rng.random_range(0..prs.len())])
+}
+
+/// How likely [`SelectionStrategy::Weighted`] is to pick a PR, relative to the others
+///
+/// Each vote already cast halves, thirds, ... the weight, and it grows as the deadline gets closer. PRs the
+/// voter already voted on are only shown once in a while, so they can change their mind.
+pub fn weight(pr: &PullRequestInfo, seen: bool, now: chrono::DateTime<chrono::Utc>) -> f64 {
+    let need = 1.0 / (1.0 + cast_votes(pr) as f64);
+    let hours_left = ((pr.deadline - now).num_seconds().max(0) as f64) / 3600.0;
+    let urgency = 1.0 + URGENT_HOURS / (URGENT_HOURS + hours_left);
+    let seen = if seen { SEEN_WEIGHT } else { 1.0 };
+    need * urgency * seen
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Direction;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    const DRAWS: usize = 20_000;
+
+    fn now() -> chrono::DateTime<chrono::Utc> {
+        "2025-01-02T12:00:00Z".parse().unwrap()
+    }
+
+    /// A queued PR numbered `number` with `votes` votes to accept, whose vote ends in `hours_left` hours
+    fn pr(number: u64, votes: usize, hours_left: i64) -> PullRequestInfo {
+        let mut pr: PullRequestInfo =
+            serde_json::from_str(include_str!("fixtures/queued_pr.json")).unwrap();
+        pr.pull_request.pr_number = number;
+        pr.deadline = now() + chrono::Duration::hours(hours_left);
+        pr.votes = (0..votes)
+            .map(|i| (format!("device:{i}"), Direction::Right))
+            .collect();
+        pr
+    }
+
+    /// How often each PR is picked in [`DRAWS`] draws, by PR number
+    fn picks(
+        strategy: SelectionStrategy,
+        prs: &[PullRequestInfo],
+        voter: Option<&str>,
+    ) -> Vec<usize> {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut counts = vec![0; prs.len()];
+        for _ in 0..DRAWS {
+            let picked = strategy.select(prs, voter, now(), &mut rng).unwrap();
+            counts[picked.pull_request.pr_number as usize] += 1;
+        }
+        counts
+    }
+
+    fn assert_share(count: usize, expected: f64) {
+        let share = count as f64 / DRAWS as f64;
+        assert!(
+            (share - expected).abs() < 0.02,
+            "picked {share:.3} of the time instead of {expected:.3}"
+        );
+    }
+
+    #[test]
+    fn uniform_picks_every_pr_as_often() {
+        let prs = [pr(0, 0, 48), pr(1, 3, 1), pr(2, 10, 200)];
+        for count in picks(SelectionStrategy::Uniform, &prs, None) {
+            assert_share(count, 1.0 / 3.0);
+        }
+    }
+
+    #[test]
+    fn weighted_picks_in_proportion_to_the_weights() {
+        let mut prs = [pr(0, 0, 48), pr(1, 3, 1), pr(2, 1, 200)];
+        prs[2].votes.insert("github:1".to_string(), Direction::Left);
+        let weights = [
+            weight(&prs[0], false, now()),
+            weight(&prs[1], false, now()),
+            weight(&prs[2], true, now()),
+        ];
+        let total: f64 = weights.iter().sum();
+
+        let counts = picks(SelectionStrategy::Weighted, &prs, Some("github:1"));
+        for (count, weight) in counts.into_iter().zip(weights) {
+            assert_share(count, weight / total);
+        }
+    }
+
+    #[test]
+    fn fewest_votes_first_always_picks_the_least_voted() {
+        let prs = [pr(0, 2, 48), pr(1, 1, 1), pr(2, 5, 200)];
+        assert_eq!(
+            picks(SelectionStrategy::FewestVotesFirst, &prs, None),
+            [0, DRAWS, 0]
+        );
+    }
+
+    #[test]
+    fn deadline_first_always_picks_the_first_to_end() {
+        let prs = [pr(0, 0, 48), pr(1, 3, 200), pr(2, 1, 2)];
+        assert_eq!(
+            picks(SelectionStrategy::DeadlineFirst, &prs, None),
+            [0, 0, DRAWS]
+        );
+    }
+
+    #[test]
+    fn ties_are_broken_at_random() {
+        let prs = [pr(0, 1, 48), pr(1, 1, 48), pr(2, 4, 48)];
+        let counts = picks(SelectionStrategy::FewestVotesFirst, &prs, None);
+        assert_share(counts[0], 0.5);
+        assert_share(counts[1], 0.5);
+        assert_eq!(counts[2], 0);
+    }
+
+    #[test]
+    fn prs_the_voter_voted_on_wait_until_there_is_nothing_else() {
+        let mut prs = [pr(0, 0, 48), pr(1, 0, 48)];
+        prs[0]
+            .votes
+            .insert("github:1".to_string(), Direction::Right);
+        assert_eq!(
+            picks(SelectionStrategy::Uniform, &prs, Some("github:1")),
+            [0, DRAWS]
+        );
+
+        prs[1].votes.insert("github:1".to_string(), Direction::Left);
+        let counts = picks(SelectionStrategy::Uniform, &prs, Some("github:1"));
+        assert_share(counts[0], 0.5);
+    }
+
+    #[test]
+    fn skipped_prs_never_come_back() {
+        let mut prs = [pr(0, 0, 1), pr(1, 5, 200)];
+        prs[0].votes.insert("github:1".to_string(), Direction::Skip);
+        for strategy in [
+            SelectionStrategy::Uniform,
+            SelectionStrategy::FewestVotesFirst,
+            SelectionStrategy::DeadlineFirst,
+            SelectionStrategy::Weighted,
+        ] {
+            assert_eq!(picks(strategy, &prs, Some("github:1")), [0, DRAWS]);
+            // Other voters still see it
+            assert_ne!(picks(strategy, &prs, Some("github:2"))[0], 0);
+
+            let mut all_skipped = prs.clone();
+            all_skipped[1]
+                .votes
+                .insert("github:1".to_string(), Direction::Skip);
+            let mut rng = StdRng::seed_from_u64(7);
+            assert_eq!(
+                strategy.select(&all_skipped, Some("github:1"), now(), &mut rng),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn a_filter_matches_when_every_set_part_does() {
+        let pr = pr(0, 0, 48).pull_request;
+        assert!(PrFilter::default().matches(&pr));
+
+        let filter = PrFilter {
+            repo_owner: Some("Octocat".to_string()),
+            repo_name: Some("hello-world".to_string()),
+            min_additions: Some(pr.additions),
+            max_deletions: Some(pr.deletions),
+            ..PrFilter::default()
+        };
+        assert!(filter.matches(&pr));
+        for other in [
+            PrFilter {
+                repo_owner: Some("someone-else".to_string()),
+                ..filter.clone()
+            },
+            PrFilter {
+                min_additions: Some(pr.additions + 1),
+                ..filter.clone()
+            },
+            PrFilter {
+                max_deletions: Some(pr.deletions.saturating_sub(1)),
+                min_deletions: Some(pr.deletions),
+                ..filter.clone()
+            },
+        ] {
+            assert!(!other.matches(&pr), "{other:?}");
+        }
+    }
+
+    #[test]
+    fn extensions_match_any_changed_file() {
+        let mut pr = pr(0, 0, 48).pull_request;
+        pr.diff = "diff --git a/src/main.rs b/src/main.rs\n+fn main() {}\n\
+                   diff --git a/docs/old.txt b/docs/Notes.MD\n"
+            .to_string();
+        let with = |extensions: &str| PrFilter {
+            extensions: Some(extensions.to_string()),
+            ..PrFilter::default()
+        };
+        assert!(with("rs").matches(&pr));
+        assert!(with("toml, .md").matches(&pr));
+        // Both sides of a rename count
+        assert!(with("txt").matches(&pr));
+        assert!(!with("toml,py").matches(&pr));
+        assert!(with(" , ").matches(&pr));
+
+        // The headers left in a listing are enough
+        let mut listed = PullRequestInfo {
+            pull_request: pr,
+            ..self::pr(0, 0, 48)
+        };
+        listed = listed.without_diff();
+        assert!(with("rs").matches(&listed.pull_request));
+    }
+}
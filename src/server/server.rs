@@ -1,10 +1,28 @@
 use axum::{
+    body::Bytes,
+    extract::{
+        rejection::{JsonRejection, QueryRejection},
+        ConnectInfo, Query,
+    },
+    http::{header::SET_COOKIE, HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        AppendHeaders, IntoResponse, Redirect, Response,
+    },
     routing::{get_service, post},
     Json, Router,
 };
 
-// 1 day
-const MERGE_MINUTES: u64 = 60 * 24;
+/// The mounted volume everything is persisted to
+pub const DATA_DIR: &str = "/data";
+/// The page the web app is served from
+const INDEX_HTML: &str = "target/dx/gitlucky/debug/web/public/index.html";
+/// How many queue events a follower of `/events` can fall behind before it misses some
+const EVENT_BUFFER: usize = 256;
+/// How many clients can follow `/events` at once. Each one holds a connection open
+const MAX_EVENT_FOLLOWERS: usize = 1000;
+/// What a request to vote without a session or device token is told
+const NOT_A_VOTER: &str = "Fetch a PR from /pr or sign in before voting";
 
 #[cfg(not(feature = "server"))]
 use dioxus::prelude::{DioxusRouterExt, ServeConfig};
@@ -12,19 +30,34 @@ use octocrab::models::{
     events::payload::{PullRequestEventAction, PullRequestEventPayload},
     pulls::PullRequestAction,
 };
-use rand::Rng;
 use serde::{Deserialize, Serialize};
-use std::{
-    collections::HashMap,
-    env,
-    io::Write,
-    sync::{Arc, RwLock},
-    time::Duration,
-};
+use std::{collections::BTreeMap, convert::Infallible, env, io::Write, net::SocketAddr, sync::Arc};
+use tokio::sync::broadcast;
+use tokio_stream::{wrappers::BroadcastStream, StreamExt};
 
 #[cfg(not(feature = "server"))]
 use crate::App;
-use crate::{Direction, PullRequest};
+use crate::{
+    ApiError, ApiErrorCode, Ballot, Direction, HistoryRecord, LotteryDraw, Outcome, PrFilter, PrId,
+    PullRequest, QueueEvent, User,
+};
+
+use crate::github_bot::bot;
+
+use super::{
+    auth::{self, Auth, CallbackQuery, OAuthConfig, Signer, Voter, SESSION_COOKIE, STATE_COOKIE},
+    github::{Bot, GitHub},
+    history::{self, HistoryQuery},
+    leaderboard,
+    policy::{DecisionPolicy, Tally},
+    queue::{self, QueueQuery},
+    rate_limit::{self, RateLimited, RateLimits},
+    repo_config::{self, ConfigError, QuorumFallback, RepoConfig, UndecidedOutcome, CONFIG_PATH},
+    scheduler::{Scheduler, SystemClock},
+    selection::SelectionStrategy,
+    store::{Store, StoreConfig},
+    webhook::{self, WebhookEvent},
+};
 
 impl PullRequest {
     pub fn get_audio_path(&self) -> String {
@@ -32,16 +65,231 @@ impl PullRequest {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PullRequestInfo {
+    pub pull_request: PullRequest,
+    /// The direction each voter swiped. Voting again replaces the earlier vote
+    pub votes: BTreeMap<String, Direction>,
+    pub creation_time: chrono::DateTime<chrono::Utc>,
+    /// When the vote on this PR is finalized
+    pub deadline: chrono::DateTime<chrono::Utc>,
+    /// Votes cast against an older revision of the PR. They are kept for the record, but don't count
+    pub stale_left_votes: usize,
+    pub stale_right_votes: usize,
+    /// The `.gitlucky.toml` of the repo when the PR was queued
+    pub config: RepoConfig,
+    /// How many times the vote was extended because it didn't reach the quorum
+    pub extensions: u32,
+    /// The seed of the lottery draw, for repos with the `lottery` policy. Secret until the vote ends
+    pub lottery_seed: Option<u64>,
+    /// Every vote that was cast, changed or retracted on this PR, oldest first
+    pub history: Vec<VoteChange>,
+}
+
+/// A voter casting, changing or retracting their vote on a PR
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VoteChange {
+    pub voter: String,
+    /// The GitHub login of the voter, if they were signed in
+    #[serde(default)]
+    pub login: Option<String>,
+    /// The vote before the change, or none if this is their first vote
+    pub from: Option<Direction>,
+    /// The vote after the change, or none if they retracted it
+    pub to: Option<Direction>,
+    /// The head SHA of the PR when the change was made
+    pub revision: String,
+    pub time: chrono::DateTime<chrono::Utc>,
+}
+
+/// A queued PR that was merged or closed on GitHub before the vote finished
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResolvedOutsideVote {
     pub pull_request: PullRequest,
     pub left_votes: usize,
     pub right_votes: usize,
-    pub creation_time: chrono::DateTime<chrono::Utc>,
+    pub merged: bool,
+    pub resolved_time: chrono::DateTime<chrono::Utc>,
+}
+
+/// What happens to the votes on a queued PR when the author pushes new commits
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnSynchronize {
+    /// Keep counting the existing votes
+    Keep,
+    /// Throw the existing votes away
+    Reset,
+    /// Keep the existing votes around as cast against an older revision
+    #[default]
+    MarkStale,
+}
+
+impl PullRequestInfo {
+    /// A copy whose diff only keeps the `diff --git` line of each file, for listing and picking PRs. The rest of
+    /// the diff is never copied
+    pub fn without_diff(&self) -> Self {
+        let PullRequest {
+            diff_url,
+            diff,
+            title,
+            additions,
+            deletions,
+            changed_files,
+            author,
+            repo_name,
+            key,
+            branch_to_merge,
+            branch_to_merge_into,
+            pr_number,
+            repo_owner,
+            profile_pic_url,
+        } = &self.pull_request;
+        Self {
+            pull_request: PullRequest {
+                diff_url: diff_url.clone(),
+                diff: repo_config::diff_headers(diff),
+                title: title.clone(),
+                additions: *additions,
+                deletions: *deletions,
+                changed_files: *changed_files,
+                author: author.clone(),
+                repo_name: repo_name.clone(),
+                key: key.clone(),
+                branch_to_merge: branch_to_merge.clone(),
+                branch_to_merge_into: branch_to_merge_into.clone(),
+                pr_number: *pr_number,
+                repo_owner: repo_owner.clone(),
+                profile_pic_url: profile_pic_url.clone(),
+            },
+            votes: self.votes.clone(),
+            creation_time: self.creation_time,
+            deadline: self.deadline,
+            stale_left_votes: self.stale_left_votes,
+            stale_right_votes: self.stale_right_votes,
+            config: self.config.clone(),
+            extensions: self.extensions,
+            lottery_seed: self.lottery_seed,
+            history: self.history.clone(),
+        }
+    }
+
+    pub fn left_votes(&self) -> usize {
+        self.count_votes(Direction::Left)
+    }
+
+    pub fn right_votes(&self) -> usize {
+        self.count_votes(Direction::Right)
+    }
+
+    pub fn abstentions(&self) -> usize {
+        self.count_votes(Direction::Skip)
+    }
+
+    /// The GitHub login `voter` last voted with, if they were signed in
+    pub fn login(&self, voter: &str) -> Option<&str> {
+        self.history
+            .iter()
+            .rev()
+            .find(|change| change.voter == voter)?
+            .login
+            .as_deref()
+    }
+
+    /// Whether `voter` skipped this PR, so it shouldn't be shown to them again
+    pub fn skipped_by(&self, voter: &str) -> bool {
+        self.votes.get(voter) == Some(&Direction::Skip)
+    }
+
+    fn count_votes(&self, direction: Direction) -> usize {
+        self.votes
+            .values()
+            .filter(|&&vote| vote == direction)
+            .count()
+    }
+
+    /// The current votes. Votes from voters who didn't sign in weigh less
+    fn tally(&self) -> Tally {
+        let weight = |direction: Direction| -> f64 {
+            self.votes
+                .iter()
+                .filter(|(_, &vote)| vote == direction)
+                .map(|(voter, _)| {
+                    if auth::is_anonymous(voter) {
+                        self.config.anonymous_weight
+                    } else {
+                        1.0
+                    }
+                })
+                .sum()
+        };
+        Tally {
+            left_votes: self.left_votes(),
+            right_votes: self.right_votes(),
+            left_weight: weight(Direction::Left),
+            right_weight: weight(Direction::Right),
+        }
+    }
+
+    /// Set the vote of `voter`, or retract it if `direction` is none, and record the change along with the
+    /// `login` of signed in voters. Does nothing if the vote stays the same. Returns whether it changed
+    fn set_vote(
+        &mut self,
+        voter: &str,
+        login: Option<&str>,
+        direction: Option<Direction>,
+        time: chrono::DateTime<chrono::Utc>,
+    ) -> bool {
+        let from = match direction {
+            Some(direction) => self.votes.insert(voter.to_string(), direction),
+            None => self.votes.remove(voter),
+        };
+        if from == direction {
+            return false;
+        }
+        self.history.push(VoteChange {
+            voter: voter.to_string(),
+            login: login.map(str::to_string),
+            from,
+            to: direction,
+            revision: self.pull_request.id().revision,
+            time,
+        });
+        true
+    }
+
+    /// Retract every vote, recording each in the history
+    fn clear_votes(&mut self, time: chrono::DateTime<chrono::Utc>) {
+        let voters: Vec<String> = self.votes.keys().cloned().collect();
+        for voter in voters {
+            let login = self.login(&voter).map(str::to_string);
+            self.set_vote(&voter, login.as_deref(), None, time);
+        }
+    }
+
+    /// Replace the PR with a newer revision of itself. Votes that don't carry over are cleared against the old
+    /// revision
+    fn synchronize(
+        &mut self,
+        pull_request: PullRequest,
+        on_synchronize: OnSynchronize,
+        time: chrono::DateTime<chrono::Utc>,
+    ) {
+        match on_synchronize {
+            OnSynchronize::Keep => {}
+            OnSynchronize::Reset => self.clear_votes(time),
+            OnSynchronize::MarkStale => {
+                self.stale_left_votes += self.left_votes();
+                self.stale_right_votes += self.right_votes();
+                self.clear_votes(time);
+            }
+        }
+        self.pull_request = pull_request;
+    }
 }
 
 impl PullRequest {
-    async fn new_from_payload(payload: PullRequestEventPayload) -> Self {
+    async fn new_from_payload(payload: PullRequestEventPayload) -> reqwest::Result<Self> {
         //println!("payload: {:?}", payload);
         let diff_url = payload.pull_request.diff_url.clone().unwrap();
         let title = payload.pull_request.title.clone().unwrap();
@@ -79,11 +327,10 @@ impl PullRequest {
         println!("Branch to merge: {}", branch_to_merge);
         println!("Branch to merge into: {}", branch_to_merge_into);
         let diff = reqwest::get(diff_url.clone())
-            .await
-            .unwrap()
+            .await?
+            .error_for_status()?
             .text()
-            .await
-            .unwrap();
+            .await?;
 
         let pr = Self {
             diff_url: diff_url.to_string(),
@@ -102,13 +349,20 @@ impl PullRequest {
             profile_pic_url,
         };
 
-        pr
+        Ok(pr)
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct Server {
-    all_prs: Arc<RwLock<HashMap<String, PullRequestInfo>>>,
+    store: Arc<dyn Store>,
+    scheduler: Scheduler,
+    github: Arc<dyn GitHub>,
+    webhook_secret: Arc<Vec<u8>>,
+    auth: Auth,
+    limits: RateLimits,
+    selection: SelectionStrategy,
+    events: broadcast::Sender<QueueEvent>,
 }
 
 impl Server {
@@ -117,32 +371,132 @@ impl Server {
         let addr = "0.0.0.0:8080";
 
         let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+        let store = StoreConfig::from_env(DATA_DIR)
+            .open()
+            .expect("Failed to open the store");
         let mut server = Self {
-            all_prs: Arc::new(RwLock::new(HashMap::new())),
+            store: Arc::from(store),
+            scheduler: Scheduler::new(SystemClock),
+            github: Arc::new(Bot),
+            webhook_secret: Arc::new(webhook::webhook_secret()),
+            auth: Auth::new(OAuthConfig::from_env(), Signer::from_env()),
+            limits: RateLimits::from_env(),
+            selection: SelectionStrategy::from_env(),
+            events: broadcast::channel(EVENT_BUFFER).0,
         };
 
         let s_c = server.clone();
         let mut router = Router::new().route(
             "/", // The github webhook
-            post(move |payload: Json<PullRequestEventPayload>| async move {
-                s_c.webhook_handler(payload).await;
+            post(move |headers: HeaderMap, body: Bytes| async move {
+                s_c.webhook_handler(headers, body).await
             })
-            .get_service(tower_http::services::ServeFile::new(
-                "target/dx/gitlucky/debug/web/public/index.html",
-            )),
+            .get_service(tower_http::services::ServeFile::new(INDEX_HTML)),
+        );
+        // Pages of the app other than the home page, so they can be loaded directly
+        router = router.route(
+            "/archive",
+            get_service(tower_http::services::ServeFile::new(INDEX_HTML)),
+        );
+        router = router.route(
+            "/leaderboard",
+            get_service(tower_http::services::ServeFile::new(INDEX_HTML)),
         );
         let s_c = server.clone();
         router = router.route(
             "/pr",
-            axum::routing::get(move || async move { Json(s_c.get_random_pr()) }),
+            axum::routing::get(
+                move |ConnectInfo(addr): ConnectInfo<SocketAddr>,
+                      headers: HeaderMap,
+                      filter: Result<Query<PrFilter>, QueryRejection>| async move {
+                    s_c.pr_handler(addr, headers, filter)
+                },
+            ),
+        );
+        let s_c = server.clone();
+        router = router.route(
+            "/prs",
+            axum::routing::get(
+                move |ConnectInfo(addr): ConnectInfo<SocketAddr>,
+                      headers: HeaderMap,
+                      query: Result<Query<QueueQuery>, QueryRejection>| async move {
+                    s_c.prs_handler(addr, headers, query)
+                },
+            ),
+        );
+        let s_c = server.clone();
+        router = router.route(
+            "/history",
+            axum::routing::get(
+                move |ConnectInfo(addr): ConnectInfo<SocketAddr>,
+                      headers: HeaderMap,
+                      query: Result<Query<HistoryQuery>, QueryRejection>| async move {
+                    s_c.history_handler(addr, headers, query)
+                },
+            ),
+        );
+        let s_c = server.clone();
+        router = router.route(
+            "/leaderboards",
+            axum::routing::get(
+                move |ConnectInfo(addr): ConnectInfo<SocketAddr>, headers: HeaderMap| async move {
+                    s_c.leaderboards_handler(addr, headers)
+                },
+            ),
+        );
+        let s_c = server.clone();
+        router = router.route(
+            "/events",
+            axum::routing::get(
+                move |ConnectInfo(addr): ConnectInfo<SocketAddr>, headers: HeaderMap| async move {
+                    s_c.events_handler(addr, headers)
+                },
+            ),
         );
         let s_c = server.clone();
         router = router.route(
             "/vote",
-            post(move |payload: Json<(String, Direction)>| async move {
-                let (diff_url, direction) = payload.0;
-                s_c.clone().vote_on_pr(diff_url, direction);
-            }),
+            post(
+                move |ConnectInfo(addr): ConnectInfo<SocketAddr>,
+                      headers: HeaderMap,
+                      payload: Result<Json<(PrId, Direction)>, JsonRejection>| async move {
+                    s_c.vote_handler(addr, headers, payload).await
+                },
+            ),
+        );
+        let s_c = server.clone();
+        router = router.route(
+            "/vote/retract",
+            post(
+                move |ConnectInfo(addr): ConnectInfo<SocketAddr>,
+                      headers: HeaderMap,
+                      payload: Result<Json<PrId>, JsonRejection>| async move {
+                    s_c.retract_handler(addr, headers, payload).await
+                },
+            ),
+        );
+        let s_c = server.clone();
+        router = router.route(
+            "/auth/login",
+            axum::routing::get(move || async move { s_c.login() }),
+        );
+        let s_c = server.clone();
+        router = router.route(
+            "/auth/callback",
+            axum::routing::get(
+                move |headers: HeaderMap, query: Query<CallbackQuery>| async move {
+                    s_c.login_callback(headers, query.0).await
+                },
+            ),
+        );
+        let s_c = server.clone();
+        router = router.route("/auth/logout", post(move || async move { s_c.logout() }));
+        let s_c = server.clone();
+        router = router.route(
+            "/auth/me",
+            axum::routing::get(
+                move |headers: HeaderMap| async move { Json(s_c.auth.user(&headers)) },
+            ),
         );
         let s_c = server.clone();
         // Gracefully shutdown the server
@@ -163,158 +517,1970 @@ impl Server {
         );
 
         server.load_prs();
-        axum::serve(listener, router).await.unwrap();
+        // Rate limiting needs the address of every client
+        axum::serve(
+            listener,
+            router.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .unwrap();
         server
     }
 
     fn load_prs(&self) {
-        // Load the prs from the file
-        let file = std::fs::File::open("/data/prs.json");
-        if file.is_err() {
-            println!("No prs.json file found, starting with empty PRs.");
-            return;
-        }
-        let file = file.unwrap();
-        let all_prs: Vec<PullRequestInfo> = serde_json::from_reader(file).unwrap();
-        let mut all_prs_map = HashMap::new();
-        for pr in all_prs {
-            all_prs_map.insert(pr.pull_request.diff_url.clone(), pr.clone());
-            // Start the vote finalization task
-            let diff_url = pr.pull_request.diff_url.clone();
-            let delay_lenth_secs = (pr.creation_time.timestamp() - chrono::Utc::now().timestamp())
-                as u64
-                + 60 * MERGE_MINUTES;
-
-            let delay_length_mins = delay_lenth_secs / 60;
-
-            let s_c = self.clone();
-            let handle =
-                tokio::spawn(async move { s_c.finalize_vote(diff_url, delay_length_mins).await });
+        for pr in self.store.all().expect("Failed to load the queue") {
             println!("Loaded PR: {:?}", pr.pull_request);
+            // Overdue PRs are finalized right away
+            self.schedule_finalize(pr.pull_request.id(), pr.deadline);
         }
-        let mut all_prs = self.all_prs.write().unwrap();
-        all_prs.clear();
-        all_prs.extend(all_prs_map);
     }
 
-    async fn webhook_handler(&self, raw_payload: Json<PullRequestEventPayload>) {
-        let payload = raw_payload.0;
-        if payload.action != PullRequestEventAction::Opened
-            && payload.action != PullRequestEventAction::Reopened
-        {
-            println!("Ignoring action: {:?}", payload.action);
-            return;
+    async fn webhook_handler(&self, headers: HeaderMap, body: Bytes) -> StatusCode {
+        let signature = headers
+            .get(webhook::SIGNATURE_HEADER)
+            .and_then(|value| value.to_str().ok());
+        if !webhook::verify_signature(&self.webhook_secret, signature, &body) {
+            println!("Rejecting webhook with an invalid signature");
+            return StatusCode::UNAUTHORIZED;
+        }
+
+        let Some(event) = headers
+            .get(webhook::EVENT_HEADER)
+            .and_then(|value| value.to_str().ok())
+        else {
+            println!("Rejecting webhook without an event header");
+            return StatusCode::BAD_REQUEST;
+        };
+        let event = match WebhookEvent::parse(event, &body) {
+            Ok(event) => event,
+            Err(e) => {
+                println!("Failed to parse {} webhook payload: {}", event, e);
+                return StatusCode::UNPROCESSABLE_ENTITY;
+            }
+        };
+
+        match event {
+            WebhookEvent::Ping(ping) => {
+                println!("Received ping for hook {:?}: {:?}", ping.hook_id, ping.zen);
+                StatusCode::OK
+            }
+            WebhookEvent::PullRequest(payload) => self.handle_pull_request(*payload).await,
+            WebhookEvent::Installation(payload) => {
+                println!(
+                    "Installation {} {} for {:?}",
+                    payload.installation.id,
+                    payload.action,
+                    payload.installation.account.map(|account| account.login)
+                );
+                StatusCode::OK
+            }
+            WebhookEvent::IssueComment(payload) => {
+                println!(
+                    "Ignoring issue comment {:?} on #{}",
+                    payload.action, payload.issue.number
+                );
+                StatusCode::OK
+            }
+            WebhookEvent::CheckSuite(payload) => {
+                println!(
+                    "Check suite {} for {}: {:?}",
+                    payload.action, payload.check_suite.head_sha, payload.check_suite.conclusion
+                );
+                StatusCode::OK
+            }
+            WebhookEvent::IgnoredAction { event, action } => {
+                println!("Ignoring {} action: {}", event, action);
+                StatusCode::OK
+            }
+            WebhookEvent::Unknown(event) => {
+                println!("Ignoring unknown event: {}", event);
+                StatusCode::ACCEPTED
+            }
+        }
+    }
+
+    async fn handle_pull_request(&self, payload: PullRequestEventPayload) -> StatusCode {
+        let handled = match payload.action {
+            PullRequestEventAction::Opened | PullRequestEventAction::Reopened => {
+                self.queue_pr(payload).await
+            }
+            PullRequestEventAction::Synchronize => self.synchronize_pr(payload).await,
+            PullRequestEventAction::Closed => self.close_pr(payload),
+            _ => {
+                println!("Ignoring action: {:?}", payload.action);
+                Ok(StatusCode::OK)
+            }
+        };
+        // GitHub doesn't redeliver failed webhooks on its own. The 500 marks the delivery as failed under the
+        // app's recent deliveries, where it can be redelivered once the store works again
+        handled.unwrap_or_else(|e| {
+            println!(
+                "Failed to handle pull request webhook, redeliver it from the app settings: {}",
+                e
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+    }
+
+    /// Refresh the diff and head SHA of a queued PR after new commits were pushed to it
+    async fn synchronize_pr(
+        &self,
+        payload: PullRequestEventPayload,
+    ) -> std::io::Result<StatusCode> {
+        let id = pr_id_from_payload(&payload);
+        let Some(queued) = self.store.get(&id)? else {
+            println!("Ignoring synchronize for PR that isn't queued: {}", id);
+            return Ok(StatusCode::OK);
+        };
+
+        let pull_request = match PullRequest::new_from_payload(payload).await {
+            Ok(pull_request) => pull_request,
+            Err(e) => {
+                println!("Failed to fetch the diff of {}: {}", id, e);
+                return Ok(StatusCode::BAD_GATEWAY);
+            }
+        };
+        let on_synchronize = queued.config.on_synchronize;
+        println!("Synchronizing PR {} ({:?} votes)", id, on_synchronize);
+        let now = self.scheduler.now();
+        let updated = self.store.update(&id, &mut |pr| {
+            pr.synchronize(pull_request.clone(), on_synchronize, now)
+        })?;
+        if let Some(pr) = updated {
+            self.publish(QueueEvent::Updated {
+                tally: queue::tally(&pr),
+                pull_request: pr.pull_request,
+            });
         }
+        Ok(StatusCode::OK)
+    }
+
+    /// Drop a PR from the queue after it was merged or closed on GitHub
+    fn close_pr(&self, payload: PullRequestEventPayload) -> std::io::Result<StatusCode> {
+        let id = pr_id_from_payload(&payload);
+        self.resolve_outside_vote(&id, payload.pull_request.merged_at.is_some())?;
+        Ok(StatusCode::OK)
+    }
+
+    fn resolve_outside_vote(&self, id: &PrId, merged: bool) -> std::io::Result<()> {
+        let Some(pr) = self.store.remove(id)? else {
+            println!("Ignoring close for PR that isn't queued: {}", id);
+            return Ok(());
+        };
+        self.scheduler.cancel(&id.key());
+        self.publish(QueueEvent::Removed {
+            id: pr.pull_request.id(),
+        });
+
+        println!(
+            "PR {} was {} outside of the vote",
+            id,
+            if merged { "merged" } else { "closed" }
+        );
+        self.store.record_resolved(ResolvedOutsideVote {
+            left_votes: pr.left_votes(),
+            right_votes: pr.right_votes(),
+            pull_request: pr.pull_request,
+            merged,
+            resolved_time: chrono::Utc::now(),
+        })
+    }
+
+    async fn queue_pr(&self, payload: PullRequestEventPayload) -> std::io::Result<StatusCode> {
         if payload.pull_request.mergeable == Some(false) {
             println!("Ignoring unmergeable PR: {:?}", payload.pull_request);
-            return;
+            return Ok(StatusCode::OK);
         }
 
-        let s_c = self.clone();
-        let diff_url = payload.pull_request.diff_url.clone().unwrap().to_string();
-        let handle = tokio::spawn(async move { s_c.finalize_vote(diff_url, MERGE_MINUTES).await });
         let creation_time = payload
             .pull_request
             .created_at
             .unwrap_or(chrono::Utc::now());
-        let pull_request = PullRequest::new_from_payload(payload.clone()).await;
-        self.all_prs.write().unwrap().insert(
-            pull_request.diff_url.clone(),
-            PullRequestInfo {
-                pull_request: pull_request.clone(),
-                left_votes: 0,
-                right_votes: 0,
-                creation_time,
-            },
-        );
+        let pull_request = match PullRequest::new_from_payload(payload).await {
+            Ok(pull_request) => pull_request,
+            Err(e) => {
+                println!("Failed to fetch the diff of the new PR: {}", e);
+                return Ok(StatusCode::BAD_GATEWAY);
+            }
+        };
+        let id = pull_request.id();
+
+        let config = match bot::load_repo_config(&pull_request.repo_owner, &pull_request.repo_name)
+            .await
+        {
+            Ok(config) => config,
+            Err(ConfigError::Invalid(errors)) => {
+                println!("Invalid config for {}: {:?}", id, errors);
+                let errors: Vec<String> = errors.iter().map(|e| format!("- {e}")).collect();
+                bot::comment(
+                    &pull_request.repo_owner,
+                    &pull_request.repo_name,
+                    pull_request.pr_number,
+                    format!(
+                        "Git Lucky couldn't start a vote on this PR because `{}` is invalid:\n\n{}\n\nFix the config and reopen the PR to try again.",
+                        CONFIG_PATH,
+                        errors.join("\n")
+                    ),
+                )
+                .await;
+                return Ok(StatusCode::OK);
+            }
+            // Voting under the defaults could ignore the paths the repo excluded, so fail the delivery instead
+            Err(ConfigError::Unavailable(e)) => {
+                println!("Failed to fetch the config for {}: {}", id, e);
+                return Ok(StatusCode::BAD_GATEWAY);
+            }
+        };
+        if !config.targets_branch(&pull_request.branch_to_merge_into) {
+            println!(
+                "Ignoring PR into {} for {}",
+                pull_request.branch_to_merge_into, id
+            );
+            return Ok(StatusCode::OK);
+        }
+        if let Some(file) = config.excluded_file(&pull_request.diff) {
+            println!("Ignoring PR that touches excluded path {}: {}", file, id);
+            bot::comment(
+                &pull_request.repo_owner,
+                &pull_request.repo_name,
+                pull_request.pr_number,
+                format!("This PR changes `{file}`, which is excluded from voting in `{CONFIG_PATH}`. It's up to the maintainers!"),
+            )
+            .await;
+            return Ok(StatusCode::OK);
+        }
+
+        let deadline = self.scheduler.now() + config.voting_duration();
+        let lottery_seed = (config.policy == DecisionPolicy::Lottery).then(LotteryDraw::new_seed);
+        let pr = PullRequestInfo {
+            pull_request: pull_request.clone(),
+            votes: BTreeMap::new(),
+            creation_time,
+            deadline,
+            stale_left_votes: 0,
+            stale_right_votes: 0,
+            config,
+            extensions: 0,
+            lottery_seed,
+            history: Vec::new(),
+        };
+        self.store.insert(pr)?;
+        self.publish(QueueEvent::Queued { id: id.clone() });
+        self.schedule_finalize(id, deadline);
+
+        if let Some(seed) = lottery_seed {
+            bot::comment(
+                &pull_request.repo_owner,
+                &pull_request.repo_name,
+                pull_request.pr_number,
+                format!(
+                    "This repo decides PRs by lottery. The seed for the draw has the SHA-256 hash `{}`, and it will be revealed when the vote ends so anyone can check the draw.",
+                    LotteryDraw::commitment(seed)
+                ),
+            )
+            .await;
+        }
+        Ok(StatusCode::OK)
     }
 
-    pub fn get_all_prs(&self) -> Vec<PullRequest> {
-        let all_prs = self.all_prs.read().unwrap();
-        all_prs
-            .iter()
-            .map(|(_, pr)| pr.pull_request.clone())
-            .collect()
+    /// Tell everyone following `/events` about a change to the queue
+    fn publish(&self, event: QueueEvent) {
+        // Sending only fails when no one is following
+        let _ = self.events.send(event);
     }
 
-    pub fn vote_on_pr(&self, diff_url: String, direction: Direction) {
-        println!("Voting on PR: {:?}, {:?}", diff_url, direction);
-        let mut all_prs = self.all_prs.write().unwrap();
-        if let Some(pr) = all_prs.get_mut(&diff_url) {
-            match direction {
-                Direction::Left => pr.left_votes += 1,
-                Direction::Right => pr.right_votes += 1,
+    /// Stream every change to the queue as it happens
+    fn events_handler(&self, addr: SocketAddr, headers: HeaderMap) -> Response {
+        let ip = self.limits.client_ip(addr, &headers);
+        if let Err(limited) = rate_limit::check(&self.limits.browse, ip, None) {
+            return limited.into_response();
+        }
+        if self.events.receiver_count() >= MAX_EVENT_FOLLOWERS {
+            return ApiError::new(
+                ApiErrorCode::Busy,
+                "Too many clients are following the queue, try again later",
+            )
+            .into_response();
+        }
+        let events = BroadcastStream::new(self.events.subscribe()).filter_map(|event| {
+            // Followers that fall too far behind miss the events they lagged on
+            let event = event.ok()?;
+            Some(Ok::<_, Infallible>(
+                Event::default().json_data(event).unwrap(),
+            ))
+        });
+        Sse::new(events)
+            .keep_alive(KeepAlive::default())
+            .into_response()
+    }
+
+    /// Search the archive of finalized PRs
+    fn history_handler(
+        &self,
+        addr: SocketAddr,
+        headers: HeaderMap,
+        query: Result<Query<HistoryQuery>, QueryRejection>,
+    ) -> Response {
+        let ip = self.limits.client_ip(addr, &headers);
+        if let Err(limited) = rate_limit::check(&self.limits.browse, ip, None) {
+            return limited.into_response();
+        }
+        let Query(query) = match query {
+            Ok(query) => query,
+            Err(rejection) => {
+                return ApiError::new(ApiErrorCode::InvalidRequest, rejection.body_text())
+                    .into_response()
+            }
+        };
+        let history = match self.store.history() {
+            Ok(history) => history,
+            Err(e) => return ApiError::from(e).into_response(),
+        };
+        match history::search(history, &query) {
+            Ok(mut page) => {
+                for record in &mut page.items {
+                    record.ballots.clear();
+                }
+                Json(page).into_response()
             }
+            Err(error) => error.into_response(),
         }
     }
 
-    async fn finalize_vote(&self, diff_url: String, delay_minutes: u64) {
-        // wait for the vote to be finalized after a certain amount of time
-        let vote_time: Duration = Duration::from_secs(60 * delay_minutes);
-        tokio::time::sleep(vote_time).await;
-        let pr = {
-            let mut all_prs = self.all_prs.write().unwrap();
-            all_prs.remove(&diff_url)
+    /// Rank voters and authors by the finalized PRs and the votes on the queue
+    fn leaderboards_handler(&self, addr: SocketAddr, headers: HeaderMap) -> Response {
+        let ip = self.limits.client_ip(addr, &headers);
+        if let Err(limited) = rate_limit::check(&self.limits.browse, ip, None) {
+            return limited.into_response();
+        }
+        let history = match self.store.history() {
+            Ok(history) => history,
+            Err(e) => return ApiError::from(e).into_response(),
         };
-        println!("Finalizing vote for PR: {:?}", pr);
+        let queue = match self.store.all_without_diffs() {
+            Ok(queue) => queue,
+            Err(e) => return ApiError::from(e).into_response(),
+        };
+        Json(leaderboard::compute(&history, &queue)).into_response()
+    }
+
+    /// List the queue a page at a time, for dashboards and other tools
+    fn prs_handler(
+        &self,
+        addr: SocketAddr,
+        headers: HeaderMap,
+        query: Result<Query<QueueQuery>, QueryRejection>,
+    ) -> Response {
+        let ip = self.limits.client_ip(addr, &headers);
+        if let Err(limited) = rate_limit::check(&self.limits.browse, ip, None) {
+            return limited.into_response();
+        }
+        let Query(query) = match query {
+            Ok(query) => query,
+            Err(rejection) => {
+                return ApiError::new(ApiErrorCode::InvalidRequest, rejection.body_text())
+                    .into_response()
+            }
+        };
+        let queue = match self.store.all_without_diffs() {
+            Ok(queue) => queue,
+            Err(e) => return ApiError::from(e).into_response(),
+        };
+        match queue::page(queue, &query) {
+            Ok(page) => Json(page).into_response(),
+            Err(error) => error.into_response(),
+        }
+    }
 
-        if let Some(pr) = pr {
-            if pr.left_votes < pr.right_votes {
-                // merge the PR
-                println!("Merging PR: {:?}", pr.pull_request);
-                crate::github_bot::bot::merge(pr).await;
+    /// Record the vote of `voter` on the revision of the PR in `id`, replacing any vote they already cast on
+    /// the PR
+    pub fn vote_on_pr(
+        &self,
+        voter: &Voter,
+        id: PrId,
+        direction: Direction,
+    ) -> Result<(), ApiError> {
+        println!("{} voting on PR: {}, {:?}", voter.id(), id, direction);
+        let now = self.scheduler.now();
+        let mut rejected = None;
+        let mut changed = false;
+        let updated = self.store.update(&id, &mut |pr| {
+            // The PR stays queued while it is being finalized, but the votes are already counted
+            if pr.deadline <= now {
+                rejected = Some(ApiError::new(
+                    ApiErrorCode::VoteClosed,
+                    format!("The vote on {} is over", id.key()),
+                ));
+            } else if pr.pull_request.id() != id {
+                rejected = Some(ApiError::new(
+                    ApiErrorCode::StaleRevision,
+                    format!("{} changed since you saw it", id.key()),
+                ));
             } else {
-                // deny the PR
-                println!("Denying PR: {:?}", pr.pull_request);
-                crate::github_bot::bot::deny_merge(pr).await;
+                changed = pr.set_vote(&voter.id(), voter.login(), Some(direction), now);
             }
+        })?;
+        let Some(pr) = updated else {
+            return Err(ApiError::new(
+                ApiErrorCode::NotFound,
+                format!("{} is not in the queue", id.key()),
+            ));
+        };
+        if let Some(error) = rejected {
+            return Err(error);
+        }
+        if changed {
+            self.publish(QueueEvent::Voted {
+                tally: queue::tally(&pr),
+                id: pr.pull_request.id(),
+            });
         }
+        Ok(())
     }
 
-    /// Get a random pull request from the list of all pull requests
-    /// Sets the key to none so that we don't just publish api keys to the world
-    fn get_random_pr(&self) -> PullRequest {
-        let all_prs = self.all_prs.read().unwrap();
-        let mut rng = rand::thread_rng();
-        let random_index = rng.random_range(0..all_prs.len());
-        let mut pr = all_prs
-            .values()
-            .nth(random_index)
-            .unwrap()
-            .pull_request
-            .clone();
-        pr.key = None;
-        pr
+    /// Move the votes a browser cast before signing in to the account it signed in with, so no one votes twice
+    /// on a PR. Where the account already voted, its own vote wins
+    fn claim_device_votes(&self, device: &str, account: &User) -> std::io::Result<()> {
+        let (login, account) = (account.login.as_str(), Voter::User(account.clone()).id());
+        let now = self.scheduler.now();
+        for pr in self.store.all()? {
+            if !pr.votes.contains_key(device) {
+                continue;
+            }
+            self.store.update(&pr.pull_request.id(), &mut |pr| {
+                if let Some(&direction) = pr.votes.get(device) {
+                    pr.set_vote(device, None, None, now);
+                    if !pr.votes.contains_key(&account) {
+                        pr.set_vote(&account, Some(login), Some(direction), now);
+                    }
+                }
+            })?;
+        }
+        Ok(())
     }
 
-    /// Shuts down the server properly, saving everything
-    pub fn shutdown(&self) {
-        println!("Server is shutting down...");
+    /// Withdraw the vote of `voter` on a PR whose vote isn't over yet
+    pub fn retract_vote(&self, voter: &Voter, id: PrId) -> Result<(), ApiError> {
+        let voter_id = voter.id();
+        println!("{} retracting vote on PR: {}", voter_id, id);
+        let now = self.scheduler.now();
+        let mut rejected = None;
+        let mut changed = false;
+        let updated = self.store.update(&id, &mut |pr| {
+            if pr.deadline <= now {
+                rejected = Some(ApiError::new(
+                    ApiErrorCode::VoteClosed,
+                    format!("The vote on {} is over", id.key()),
+                ));
+            } else if !pr.votes.contains_key(&voter_id) {
+                rejected = Some(ApiError::new(
+                    ApiErrorCode::NotFound,
+                    format!("You haven't voted on {}", id.key()),
+                ));
+            } else {
+                changed = pr.set_vote(&voter_id, voter.login(), None, now);
+            }
+        })?;
+        let Some(pr) = updated else {
+            return Err(ApiError::new(
+                ApiErrorCode::NotFound,
+                format!("{} is not in the queue", id.key()),
+            ));
+        };
+        if let Some(error) = rejected {
+            return Err(error);
+        }
+        if changed {
+            self.publish(QueueEvent::Voted {
+                tally: queue::tally(&pr),
+                id: pr.pull_request.id(),
+            });
+        }
+        Ok(())
+    }
 
-        // Create directory if it doesn't exist
-        std::fs::create_dir_all("/data").unwrap_or_else(|e| {
-            println!("Failed to create /data directory: {}", e);
-        });
+    /// Rate limit a request to change a vote and find out who sent it, if anyone
+    fn voter_for_vote(
+        &self,
+        addr: SocketAddr,
+        headers: &HeaderMap,
+    ) -> Result<Option<Voter>, RateLimited> {
+        let ip = self.limits.client_ip(addr, headers);
+        let voter = self.auth.voter(headers);
+        let voter_id = voter.as_ref().map(Voter::id);
+        rate_limit::check(&self.limits.vote, ip, voter_id.as_deref())?;
+        Ok(voter)
+    }
+
+    /// Cast or change a vote. Voting again before the deadline replaces the earlier vote
+    async fn vote_handler(
+        &self,
+        addr: SocketAddr,
+        headers: HeaderMap,
+        payload: Result<Json<(PrId, Direction)>, JsonRejection>,
+    ) -> Response {
+        let voter = match self.voter_for_vote(addr, &headers) {
+            Ok(Some(voter)) => voter,
+            Ok(None) => {
+                return ApiError::new(ApiErrorCode::Unauthorized, NOT_A_VOTER).into_response()
+            }
+            Err(limited) => return limited.into_response(),
+        };
+        let Json((id, direction)) = match payload {
+            Ok(payload) => payload,
+            Err(rejection) => {
+                return ApiError::new(ApiErrorCode::InvalidRequest, rejection.body_text())
+                    .into_response()
+            }
+        };
+        match self.vote_on_pr(&voter, id, direction) {
+            Ok(()) => StatusCode::NO_CONTENT.into_response(),
+            Err(error) => error.into_response(),
+        }
+    }
 
-        // Save the state of the server to the mounted volume
-        match std::fs::File::create("/data/prs.json") {
-            Ok(mut file) => {
-                let all_prs = self.all_prs.read().unwrap();
-                let all_prs_vec: Vec<PullRequestInfo> = all_prs.values().cloned().collect();
-
-                match serde_json::to_string_pretty(&all_prs_vec) {
-                    Ok(json) => {
-                        use std::io::Write;
-                        if let Err(e) = file.write_all(json.as_bytes()) {
-                            println!("Failed to write data: {}", e);
-                        } else {
-                            println!("Successfully saved data to /data/prs.json");
-                        }
+    async fn retract_handler(
+        &self,
+        addr: SocketAddr,
+        headers: HeaderMap,
+        payload: Result<Json<PrId>, JsonRejection>,
+    ) -> Response {
+        let voter = match self.voter_for_vote(addr, &headers) {
+            Ok(Some(voter)) => voter,
+            Ok(None) => {
+                return ApiError::new(ApiErrorCode::Unauthorized, NOT_A_VOTER).into_response()
+            }
+            Err(limited) => return limited.into_response(),
+        };
+        let Json(id) = match payload {
+            Ok(payload) => payload,
+            Err(rejection) => {
+                return ApiError::new(ApiErrorCode::InvalidRequest, rejection.body_text())
+                    .into_response()
+            }
+        };
+        match self.retract_vote(&voter, id) {
+            Ok(()) => StatusCode::NO_CONTENT.into_response(),
+            Err(error) => error.into_response(),
+        }
+    }
+
+    /// Hand out a PR that matches `filter` to vote on, along with a device token for browsers that don't have
+    /// one yet. Answers 204 when no queued PR matches
+    fn pr_handler(
+        &self,
+        addr: SocketAddr,
+        headers: HeaderMap,
+        filter: Result<Query<PrFilter>, QueryRejection>,
+    ) -> Response {
+        let ip = self.limits.client_ip(addr, &headers);
+        let voter_id = self.auth.voter(&headers).map(|voter| voter.id());
+        if let Err(limited) = rate_limit::check(&self.limits.pr, ip, voter_id.as_deref()) {
+            return limited.into_response();
+        }
+        let Query(filter) = match filter {
+            Ok(filter) => filter,
+            Err(rejection) => {
+                return ApiError::new(ApiErrorCode::InvalidRequest, rejection.body_text())
+                    .into_response()
+            }
+        };
+        let pr = match self.select_pr(voter_id.as_deref(), &filter) {
+            Ok(Some(pr)) => Json(pr).into_response(),
+            Ok(None) => StatusCode::NO_CONTENT.into_response(),
+            Err(e) => return ApiError::from(e).into_response(),
+        };
+        if self.auth.device(&headers).is_some() {
+            return pr;
+        }
+        // Past the limit the PR is still shown, but voting on it needs signing in
+        if rate_limit::check(&self.limits.device, ip, None).is_err() {
+            return pr;
+        }
+        (AppendHeaders([(SET_COOKIE, self.auth.issue_device())]), pr).into_response()
+    }
+
+    /// Send the voter to GitHub to sign in
+    fn login(&self) -> Response {
+        match self.auth.login() {
+            Some((url, state_cookie)) => (
+                AppendHeaders([(SET_COOKIE, state_cookie)]),
+                Redirect::to(&url),
+            )
+                .into_response(),
+            None => ApiError::new(ApiErrorCode::NotFound, "Signing in is disabled").into_response(),
+        }
+    }
+
+    /// GitHub sends the voter back here after they signed in
+    async fn login_callback(&self, headers: HeaderMap, query: CallbackQuery) -> Response {
+        match self
+            .auth
+            .callback(&headers, &query.code, &query.state)
+            .await
+        {
+            Ok((user, session_cookie)) => {
+                println!("Signed in {}", user.login);
+                if let Some(device) = self.auth.device(&headers) {
+                    if let Err(e) = self.claim_device_votes(&Voter::Device(device).id(), &user) {
+                        println!(
+                            "Failed to move the votes of {} to their account: {}",
+                            user.login, e
+                        );
                     }
-                    Err(e) => println!("Failed to serialize data: {}", e),
                 }
+                let clear_state = auth::set_cookie(STATE_COOKIE, "", chrono::Duration::zero());
+                (
+                    AppendHeaders([(SET_COOKIE, session_cookie), (SET_COOKIE, clear_state)]),
+                    Redirect::to("/"),
+                )
+                    .into_response()
+            }
+            Err(e) => {
+                println!("Failed to sign in: {}", e);
+                StatusCode::BAD_REQUEST.into_response()
+            }
+        }
+    }
+
+    fn logout(&self) -> Response {
+        let clear_session = auth::set_cookie(SESSION_COOKIE, "", chrono::Duration::zero());
+        (
+            StatusCode::NO_CONTENT,
+            AppendHeaders([(SET_COOKIE, clear_session)]),
+        )
+            .into_response()
+    }
+
+    /// Finalize the vote on a PR once its deadline passes
+    fn schedule_finalize(&self, id: PrId, deadline: chrono::DateTime<chrono::Utc>) {
+        let s_c = self.clone();
+        self.scheduler
+            .schedule(id.key(), deadline, move || async move {
+                s_c.finalize_vote(id).await
+            });
+    }
+
+    /// Try to finalize the vote on a PR again a bit later, after the store failed
+    fn retry_finalize(&self, id: PrId, e: std::io::Error) {
+        println!("Failed to finalize PR {}, retrying in a minute: {}", id, e);
+        self.schedule_finalize(id, self.scheduler.now() + chrono::Duration::minutes(1));
+    }
+
+    async fn finalize_vote(&self, id: PrId) {
+        let pr = match self.store.get(&id) {
+            Ok(Some(pr)) => pr,
+            Ok(None) => return,
+            Err(e) => return self.retry_finalize(id, e),
+        };
+        // Abstentions are reported, but don't count toward the quorum. Anonymous votes count with their weight
+        let votes = pr.left_votes() + pr.right_votes();
+        let counted = pr.tally();
+        let weight = counted.weight();
+        let quorum = pr.config.quorum;
+        let below_quorum = weight < quorum as f64;
+        let can_extend = pr.extensions < pr.config.max_extensions;
+        if below_quorum && can_extend {
+            let reason = format!("Only {weight} of the {quorum} votes needed were cast");
+            return self.extend_vote(pr, reason).await;
+        }
+
+        // Votes that met the quorum but don't decide the PR, and what the repo wants to happen then
+        let undecided = if below_quorum {
+            None
+        } else if votes == 0 {
+            Some((pr.config.on_no_votes, "No one voted"))
+        } else if pr.config.policy.is_tie(counted) {
+            Some((pr.config.on_tie, "The vote ended in a tie"))
+        } else {
+            None
+        };
+        if let Some((UndecidedOutcome::Extend, reason)) = undecided {
+            if can_extend {
+                return self.extend_vote(pr, reason.to_string()).await;
+            }
+        }
+
+        let pr = match self.store.remove(&id) {
+            Ok(Some(pr)) => pr,
+            Ok(None) => return,
+            Err(e) => return self.retry_finalize(id, e),
+        };
+        println!("Finalizing vote for PR: {:?}", pr);
+        let tally = format!(
+            "{} accepted, {} denied, {} abstained.",
+            pr.right_votes(),
+            pr.left_votes(),
+            pr.abstentions()
+        );
+
+        if below_quorum {
+            let missed = missed_quorum(weight, quorum, pr.extensions);
+            match pr.config.quorum_fallback {
+                QuorumFallback::Merge => {
+                    println!("Merging PR without quorum: {:?}", pr.pull_request);
+                    let comment = format!("{missed} The repo merges PRs without a quorum, so this PR is accepted! {tally}");
+                    self.merge(pr, comment, None).await;
+                }
+                QuorumFallback::Close => {
+                    println!("Denying PR without quorum: {:?}", pr.pull_request);
+                    let comment = format!("{missed} The repo closes PRs without a quorum, so this PR is denied! {tally}");
+                    self.deny(pr, comment, None).await;
+                }
+                QuorumFallback::LeaveOpen => {
+                    println!("Leaving PR without quorum open: {:?}", pr.pull_request);
+                    let comment = format!("{missed} The vote is over, so this PR is left for the maintainers. {tally}");
+                    self.leave_open(pr, comment).await;
+                }
+            }
+            return;
+        }
+
+        if let Some((outcome, reason)) = undecided {
+            match outcome {
+                UndecidedOutcome::Accept => {
+                    println!("Merging undecided PR: {:?}", pr.pull_request);
+                    let comment = format!("{reason}, and the repo accepts PRs like that, so this PR is accepted! {tally}");
+                    self.merge(pr, comment, None).await;
+                }
+                UndecidedOutcome::Deny => {
+                    println!("Denying undecided PR: {:?}", pr.pull_request);
+                    let comment = format!("{reason}, and the repo denies PRs like that, so this PR is denied! {tally}");
+                    self.deny(pr, comment, None).await;
+                }
+                UndecidedOutcome::Extend => {
+                    println!("Leaving undecided PR open: {:?}", pr.pull_request);
+                    let comment = format!(
+                        "{reason}{}, so this PR is left for the maintainers. {tally}",
+                        even_after(pr.extensions)
+                    );
+                    self.leave_open(pr, comment).await;
+                }
+                UndecidedOutcome::Maintainer => {
+                    println!("Leaving undecided PR open: {:?}", pr.pull_request);
+                    let comment =
+                        format!("{reason}, so this PR is left for the maintainers. {tally}");
+                    self.leave_open(pr, comment).await;
+                }
+            }
+            return;
+        }
+
+        let decision = pr
+            .config
+            .policy
+            .decide(counted, &pr.pull_request.id(), pr.lottery_seed);
+        let explanation = decision.explanation;
+        let lottery = decision.lottery;
+        if decision.accepted {
+            // merge the PR
+            println!("Merging PR: {:?}", pr.pull_request);
+            let comment =
+                format!("The people have spoken and have accepted this PR! {tally} {explanation}");
+            self.merge(pr, comment, lottery).await;
+        } else {
+            // deny the PR
+            println!("Denying PR: {:?}", pr.pull_request);
+            let comment =
+                format!("The people have spoken and have denied this PR! {tally} {explanation}");
+            self.deny(pr, comment, lottery).await;
+        }
+    }
+
+    /// Merge a finalized PR and archive the result, along with the lottery draw that decided it if there was one
+    async fn merge(&self, pr: PullRequestInfo, comment: String, lottery: Option<LotteryDraw>) {
+        let mut record = history_record(&pr, &comment, Outcome::Merged, self.scheduler.now());
+        record.lottery = lottery;
+        match self.github.merge(pr, comment).await {
+            Ok(sha) => record.merge_sha = Some(sha),
+            Err(error) => {
+                record.outcome = Outcome::MergeFailed;
+                record.error = Some(error);
             }
-            Err(e) => println!("Failed to create file: {}", e),
         }
+        self.archive(record);
+    }
+
+    /// Close a finalized PR and archive the result
+    async fn deny(&self, pr: PullRequestInfo, comment: String, lottery: Option<LotteryDraw>) {
+        let mut record = history_record(&pr, &comment, Outcome::Closed, self.scheduler.now());
+        record.lottery = lottery;
+        record.error = self.github.deny_merge(pr, comment).await.err();
+        self.archive(record);
+    }
+
+    /// Leave a finalized PR for the maintainers and archive the result
+    async fn leave_open(&self, pr: PullRequestInfo, comment: String) {
+        let record = history_record(&pr, &comment, Outcome::LeftOpen, self.scheduler.now());
+        self.github.comment(&pr.pull_request, comment).await;
+        self.archive(record);
+    }
+
+    /// Add a finalized PR to the history. The PR already left the queue, so all that's left to do on failure
+    /// is to log it
+    fn archive(&self, record: HistoryRecord) {
+        if let Err(e) = self.store.record_history(record.clone()) {
+            println!("Failed to archive {:?}: {}", record, e);
+        }
+    }
+
+    /// Give a PR whose vote didn't decide anything more time. `reason` says why
+    async fn extend_vote(&self, pr: PullRequestInfo, reason: String) {
+        let id = pr.pull_request.id();
+        let deadline = self.scheduler.now() + pr.config.extension_duration();
+        let updated = self.store.update(&id, &mut |pr| {
+            pr.extensions += 1;
+            pr.deadline = deadline;
+        });
+        let pr = match updated {
+            Ok(Some(pr)) => pr,
+            Ok(None) => return,
+            Err(e) => return self.retry_finalize(id, e),
+        };
+        self.schedule_finalize(id.clone(), deadline);
+        self.publish(QueueEvent::Extended { id, deadline });
+
+        println!(
+            "Extending vote for PR {:?} until {}",
+            pr.pull_request, deadline
+        );
+        let comment = format!(
+            "{reason}, so the vote is extended until {} (extension {} of {}).",
+            deadline.format("%Y-%m-%d %H:%M UTC"),
+            pr.extensions,
+            pr.config.max_extensions
+        );
+        self.github.comment(&pr.pull_request, comment).await;
+    }
+
+    /// Pick the next pull request that matches `filter` to show `voter` with the configured
+    /// [`SelectionStrategy`], or none if there is nothing left for them to vote on
+    fn select_pr(
+        &self,
+        voter: Option<&str>,
+        filter: &PrFilter,
+    ) -> std::io::Result<Option<PullRequest>> {
+        let queue: Vec<PullRequestInfo> = self
+            .store
+            .all_without_diffs()?
+            .into_iter()
+            .filter(|pr| filter.matches(&pr.pull_request))
+            .collect();
+        let Some(picked) =
+            self.selection
+                .select(&queue, voter, self.scheduler.now(), &mut rand::rng())
+        else {
+            return Ok(None);
+        };
+        // Only the picked PR is loaded with its diff. It can leave the queue in between
+        Ok(self
+            .store
+            .get(&picked.pull_request.id())?
+            .map(|pr| pr.pull_request))
+    }
+
+    /// Shuts down the server properly. Every change is already in the store, so this only tidies it up
+    pub fn shutdown(&self) {
+        println!("Server is shutting down...");
+        if let Err(e) = self.store.flush() {
+            println!("Failed to flush the store: {}", e);
+        }
+    }
+}
+
+/// Why a vote that missed the quorum ended the way it did
+fn missed_quorum(weight: f64, quorum: usize, extensions: u32) -> String {
+    format!(
+        "Only {weight} of the {quorum} votes needed were cast{}.",
+        even_after(extensions)
+    )
+}
+
+/// The end of a sentence on why a vote ended undecided, counting the extensions it went through first
+fn even_after(extensions: u32) -> String {
+    match extensions {
+        0 => String::new(),
+        1 => ", even after an extension".to_string(),
+        _ => format!(", even after {extensions} extensions"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{
+        github::GitHub,
+        rate_limit::{Limit, RateLimiter},
+        scheduler::testing::MockClock,
+        store::{MemoryStore, State},
+    };
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+    use std::{
+        future::Future,
+        net::{IpAddr, Ipv4Addr},
+        pin::Pin,
+        sync::Mutex,
+    };
+
+    const WEBHOOK_SECRET: &[u8] = b"gitlucky-test-secret";
+
+    fn test_server() -> Server {
+        Server {
+            store: Arc::new(MemoryStore::default()),
+            scheduler: Scheduler::new(SystemClock),
+            github: Arc::new(FakeGitHub::default()),
+            webhook_secret: Arc::new(WEBHOOK_SECRET.to_vec()),
+            auth: Auth::new(None, Signer::new(b"test-session-secret")),
+            limits: RateLimits::default(),
+            selection: SelectionStrategy::default(),
+            events: broadcast::channel(EVENT_BUFFER).0,
+        }
+    }
+
+    const CLIENT: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000);
+
+    /// The body of a `/vote` request
+    fn vote(id: &PrId, direction: Direction) -> Result<Json<(PrId, Direction)>, JsonRejection> {
+        Ok(Json((id.clone(), direction)))
+    }
+
+    /// The merge commit of every PR [`FakeGitHub`] merges
+    const MERGE_SHA: &str = "e5bd3914e2e596debea16f433f57875b5b90bcd6";
+
+    /// Something the server did on GitHub, with the comment it left
+    #[derive(Debug, Clone, PartialEq)]
+    enum Action {
+        Merge(String),
+        Deny(String),
+        Comment(String),
+    }
+
+    /// Records what the server does on GitHub instead of doing it. With a `refusal`, GitHub refuses to merge or
+    /// close PRs with it as the reason
+    #[derive(Default)]
+    struct FakeGitHub {
+        actions: Mutex<Vec<Action>>,
+        refusal: Option<String>,
+    }
+
+    impl FakeGitHub {
+        fn record<T: Send + 'static>(
+            &self,
+            action: Action,
+            result: T,
+        ) -> Pin<Box<dyn Future<Output = T> + Send>> {
+            self.actions.lock().unwrap().push(action);
+            Box::pin(std::future::ready(result))
+        }
+
+        /// Everything done since the last call
+        fn take(&self) -> Vec<Action> {
+            std::mem::take(&mut self.actions.lock().unwrap())
+        }
+    }
+
+    impl GitHub for FakeGitHub {
+        fn merge(
+            &self,
+            _: PullRequestInfo,
+            comment: String,
+        ) -> Pin<Box<dyn Future<Output = Result<String, String>> + Send>> {
+            let merged = match &self.refusal {
+                Some(refusal) => Err(refusal.clone()),
+                None => Ok(MERGE_SHA.to_string()),
+            };
+            self.record(Action::Merge(comment), merged)
+        }
+
+        fn deny_merge(
+            &self,
+            _: PullRequestInfo,
+            comment: String,
+        ) -> Pin<Box<dyn Future<Output = Result<(), String>> + Send>> {
+            let closed = self.refusal.clone().map_or(Ok(()), Err);
+            self.record(Action::Deny(comment), closed)
+        }
+
+        fn comment(
+            &self,
+            _: &PullRequest,
+            comment: String,
+        ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+            self.record(Action::Comment(comment), ())
+        }
+    }
+
+    /// A server on a clock the test moves, with `pr` queued and its vote scheduled
+    fn finalizing_server(pr: PullRequestInfo) -> (Server, MockClock, Arc<FakeGitHub>) {
+        finalizing_server_with(pr, FakeGitHub::default())
+    }
+
+    /// Like [`finalizing_server`], on `github`
+    fn finalizing_server_with(
+        pr: PullRequestInfo,
+        github: FakeGitHub,
+    ) -> (Server, MockClock, Arc<FakeGitHub>) {
+        let clock = MockClock::new();
+        let github = Arc::new(github);
+        let server = Server {
+            scheduler: Scheduler::new(clock.clone()),
+            github: github.clone(),
+            ..test_server()
+        };
+        let id = pr.pull_request.id();
+        let deadline = server.scheduler.now() + pr.config.voting_duration();
+        server
+            .store
+            .insert(PullRequestInfo { deadline, ..pr })
+            .unwrap();
+        server.schedule_finalize(id, deadline);
+        (server, clock, github)
+    }
+
+    /// The fixture PR under `config`, with `left` votes to deny and `right` to merge
+    fn pr_with_votes(config: &str, left: usize, right: usize) -> PullRequestInfo {
+        let left = (0..left).map(|i| (format!("github:{i}"), Direction::Left));
+        let right =
+            (left.len()..left.len() + right).map(|i| (format!("github:{i}"), Direction::Right));
+        PullRequestInfo {
+            votes: left.chain(right).collect(),
+            config: RepoConfig::parse(config).unwrap(),
+            ..queued_pr()
+        }
+    }
+
+    fn ping_headers(signature: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(webhook::EVENT_HEADER, "ping".parse().unwrap());
+        headers.insert(webhook::SIGNATURE_HEADER, signature.parse().unwrap());
+        headers
+    }
+
+    /// A browser voting with device token `token`
+    fn device(token: &str) -> Voter {
+        Voter::Device(token.to_string())
+    }
+
+    /// The signed in GitHub user with `id`
+    fn user(id: u64) -> Voter {
+        Voter::User(User {
+            id,
+            login: format!("user-{id}"),
+        })
+    }
+
+    /// PR 7 of octocat/hello-world, with 2 votes to deny and 3 to merge
+    fn queued_pr() -> PullRequestInfo {
+        serde_json::from_str(include_str!("fixtures/queued_pr.json")).unwrap()
+    }
+
+    fn new_revision() -> PullRequest {
+        PullRequest {
+            key: Some("f4a7d2c08e3b9d1a5c6e7f8091a2b3c4d5e6f708".to_string()),
+            ..queued_pr().pull_request
+        }
+    }
+
+    #[test]
+    fn synchronizing_can_keep_the_votes() {
+        let mut pr = queued_pr();
+        pr.synchronize(new_revision(), OnSynchronize::Keep, chrono::Utc::now());
+        assert_eq!(pr.pull_request.key, new_revision().key);
+        assert_eq!((pr.left_votes(), pr.right_votes()), (2, 3));
+        assert_eq!((pr.stale_left_votes, pr.stale_right_votes), (0, 0));
+        assert!(pr.history.is_empty());
+    }
+
+    #[test]
+    fn synchronizing_can_reset_the_votes() {
+        let mut pr = queued_pr();
+        let time = chrono::Utc::now();
+        pr.synchronize(new_revision(), OnSynchronize::Reset, time);
+        assert_eq!(pr.pull_request.key, new_revision().key);
+        assert_eq!((pr.left_votes(), pr.right_votes()), (0, 0));
+        assert_eq!((pr.stale_left_votes, pr.stale_right_votes), (0, 0));
+        // The cleared votes are recorded against the revision they were cast on
+        assert_eq!(pr.history.len(), 5);
+        assert_eq!(
+            pr.history[0],
+            VoteChange {
+                voter: "github:1".to_string(),
+                login: None,
+                from: Some(Direction::Left),
+                to: None,
+                revision: queued_pr().pull_request.id().revision,
+                time,
+            }
+        );
+    }
+
+    #[test]
+    fn synchronizing_marks_the_votes_stale_by_default() {
+        let mut pr = queued_pr();
+        pr.synchronize(new_revision(), OnSynchronize::default(), chrono::Utc::now());
+        assert_eq!((pr.left_votes(), pr.right_votes()), (0, 0));
+        assert_eq!((pr.stale_left_votes, pr.stale_right_votes), (2, 3));
+        assert!(pr.history.iter().all(|change| change.to.is_none()));
+        assert_eq!(pr.history.len(), 5);
+        // Stale votes from several pushes add up
+        pr.votes.insert("github:6".to_string(), Direction::Right);
+        pr.synchronize(new_revision(), OnSynchronize::MarkStale, chrono::Utc::now());
+        assert_eq!((pr.stale_left_votes, pr.stale_right_votes), (2, 4));
+        assert_eq!(pr.history.len(), 6);
+    }
+
+    #[tokio::test]
+    async fn closing_a_pr_drops_it_from_the_queue() {
+        let server = test_server();
+        let pr = queued_pr();
+        let id = pr.pull_request.id();
+        server.store.insert(pr).unwrap();
+        server.schedule_finalize(
+            id.clone(),
+            chrono::Utc::now() + RepoConfig::default().voting_duration(),
+        );
+
+        server.resolve_outside_vote(&id, true).unwrap();
+        assert!(server.store.all().unwrap().is_empty());
+        // The vote was cancelled, so there is nothing left to cancel
+        assert!(!server.scheduler.cancel(&id.key()));
+        let resolved = server.store.resolved().unwrap();
+        assert_eq!(resolved.len(), 1);
+        assert!(resolved[0].merged);
+        assert_eq!((resolved[0].left_votes, resolved[0].right_votes), (2, 3));
+    }
+
+    #[tokio::test]
+    async fn closing_a_pr_that_is_not_queued_does_nothing() {
+        let server = test_server();
+        let other = PrId {
+            number: 8,
+            ..queued_pr().pull_request.id()
+        };
+        server.resolve_outside_vote(&other, false).unwrap();
+        assert!(server.store.resolved().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn votes_are_finalized_at_the_deadline() {
+        let (server, clock, github) = finalizing_server(pr_with_votes("", 2, 3));
+        clock.advance(60 * 24 - 1).await;
+        assert!(github.take().is_empty());
+        clock.advance(1).await;
+        assert_eq!(
+            github.take(),
+            [Action::Merge(
+                "The people have spoken and have accepted this PR! 3 accepted, 2 denied, 0 abstained. The majority decides: 3.00 for accepting, 2.00 for denying."
+                    .to_string()
+            )]
+        );
+        assert!(server.store.all().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_vote_without_votes_is_denied() {
+        let (_server, clock, github) = finalizing_server(pr_with_votes("", 0, 0));
+        clock.advance(60 * 24).await;
+        assert_eq!(
+            github.take(),
+            [Action::Deny(
+                "No one voted, and the repo denies PRs like that, so this PR is denied! 0 accepted, 0 denied, 0 abstained.".to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_tied_vote_is_denied() {
+        let (_server, clock, github) = finalizing_server(pr_with_votes("", 2, 2));
+        clock.advance(60 * 24).await;
+        assert_eq!(
+            github.take(),
+            [Action::Deny(
+                "The vote ended in a tie, and the repo denies PRs like that, so this PR is denied! 2 accepted, 2 denied, 0 abstained.".to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_vote_without_votes_can_be_accepted() {
+        let (_server, clock, github) =
+            finalizing_server(pr_with_votes("on_no_votes = \"accept\"", 0, 0));
+        clock.advance(60 * 24).await;
+        assert_eq!(
+            github.take(),
+            [Action::Merge(
+                "No one voted, and the repo accepts PRs like that, so this PR is accepted! 0 accepted, 0 denied, 0 abstained.".to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_tied_vote_can_be_left_to_the_maintainers() {
+        let (server, clock, github) =
+            finalizing_server(pr_with_votes("on_tie = \"maintainer\"", 1, 1));
+        clock.advance(60 * 24).await;
+        assert_eq!(
+            github.take(),
+            [Action::Comment(
+                "The vote ended in a tie, so this PR is left for the maintainers. 1 accepted, 1 denied, 0 abstained.".to_string()
+            )]
+        );
+        assert!(server.store.all().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_tied_vote_is_extended_until_the_extensions_run_out() {
+        let config = "on_tie = \"extend\"\nmax_extensions = 1\nextension_minutes = 60";
+        let (server, clock, github) = finalizing_server(pr_with_votes(config, 1, 1));
+        clock.advance(60 * 24).await;
+        assert_eq!(
+            github.take(),
+            [Action::Comment(
+                "The vote ended in a tie, so the vote is extended until 2025-01-02 13:00 UTC (extension 1 of 1).".to_string()
+            )]
+        );
+        assert_eq!(server.store.all().unwrap().len(), 1);
+
+        clock.advance(60).await;
+        assert_eq!(
+            github.take(),
+            [Action::Comment(
+                "The vote ended in a tie, even after an extension, so this PR is left for the maintainers. 1 accepted, 1 denied, 0 abstained.".to_string()
+            )]
+        );
+        assert!(server.store.all().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_vote_without_votes_is_left_open_when_it_cant_be_extended() {
+        let (_server, clock, github) =
+            finalizing_server(pr_with_votes("on_no_votes = \"extend\"", 0, 0));
+        clock.advance(60 * 24).await;
+        assert_eq!(
+            github.take(),
+            [Action::Comment(
+                "No one voted, so this PR is left for the maintainers. 0 accepted, 0 denied, 0 abstained."
+                    .to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn supermajorities_never_tie() {
+        let config = "on_tie = \"accept\"\npolicy = { kind = \"supermajority\", percent = 50 }";
+        let (_server, clock, github) = finalizing_server(pr_with_votes(config, 1, 1));
+        clock.advance(60 * 24).await;
+        assert_eq!(
+            github.take(),
+            [Action::Merge(
+                "The people have spoken and have accepted this PR! 1 accepted, 1 denied, 0 abstained. 50.0% accepted, and 50% is needed to merge.".to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_vote_that_misses_the_quorum_is_extended_before_the_fallback() {
+        let config = "voting_minutes = 60\nquorum = 3\nmax_extensions = 2\nextension_minutes = 30";
+        let (server, clock, github) = finalizing_server(pr_with_votes(config, 0, 1));
+        let id = queued_pr().pull_request.id();
+
+        clock.advance(60).await;
+        assert_eq!(
+            github.take(),
+            [Action::Comment(
+                "Only 1 of the 3 votes needed were cast, so the vote is extended until 2025-01-01 13:30 UTC (extension 1 of 2).".to_string()
+            )]
+        );
+        let pr = server.store.get(&id).unwrap().unwrap();
+        assert_eq!(pr.extensions, 1);
+        assert_eq!(
+            pr.deadline,
+            server.scheduler.now() + chrono::Duration::minutes(30)
+        );
+
+        clock.advance(30).await;
+        assert_eq!(github.take().len(), 1);
+        clock.advance(30).await;
+        assert_eq!(
+            github.take(),
+            [Action::Deny(
+                "Only 1 of the 3 votes needed were cast, even after 2 extensions. The repo closes PRs without a quorum, so this PR is denied! 1 accepted, 0 denied, 0 abstained.".to_string()
+            )]
+        );
+        assert!(server.store.all().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_vote_that_reaches_the_quorum_during_an_extension_is_decided_by_the_votes() {
+        let config = "quorum = 3\nmax_extensions = 1";
+        let (server, clock, github) = finalizing_server(pr_with_votes(config, 1, 1));
+        let id = queued_pr().pull_request.id();
+
+        clock.advance(60 * 24).await;
+        assert_eq!(github.take().len(), 1);
+        server
+            .store
+            .update(&id, &mut |pr| {
+                pr.votes.insert("github:3".to_string(), Direction::Right);
+            })
+            .unwrap();
+        clock.advance(60 * 24).await;
+        assert_eq!(
+            github.take(),
+            [Action::Merge(
+                "The people have spoken and have accepted this PR! 2 accepted, 1 denied, 0 abstained. The majority decides: 2.00 for accepting, 1.00 for denying."
+                    .to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_vote_that_misses_the_quorum_without_extensions_falls_back_right_away() {
+        let config = "quorum = 3\nquorum_fallback = \"leave_open\"";
+        let (server, clock, github) = finalizing_server(pr_with_votes(config, 1, 0));
+        clock.advance(60 * 24).await;
+        assert_eq!(
+            github.take(),
+            [Action::Comment(
+                "Only 1 of the 3 votes needed were cast. The vote is over, so this PR is left for the maintainers. 0 accepted, 1 denied, 0 abstained.".to_string()
+            )]
+        );
+        assert!(server.store.all().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_lottery_reveals_the_seed_it_committed_to() {
+        let pr = PullRequestInfo {
+            lottery_seed: Some(42),
+            ..pr_with_votes("policy = { kind = \"lottery\" }", 1, 1)
+        };
+        let (server, clock, github) = finalizing_server(pr);
+        clock.advance(60 * 24).await;
+        let [Action::Merge(comment) | Action::Deny(comment)] = &github.take()[..] else {
+            panic!("the lottery didn't decide the PR");
+        };
+        assert!(comment.contains("seed `42`"));
+        assert!(comment.contains(&LotteryDraw::commitment(42)));
+        let [record] = &server.store.history().unwrap()[..] else {
+            panic!("the decision wasn't archived");
+        };
+        assert_eq!(record.lottery.as_ref().map(|draw| draw.seed), Some(42));
+    }
+
+    #[tokio::test]
+    async fn finalized_votes_are_archived() {
+        let (server, clock, github) = finalizing_server(pr_with_votes("", 2, 3));
+        clock.advance(60 * 24).await;
+        let [Action::Merge(comment)] = &github.take()[..] else {
+            panic!("the PR wasn't merged");
+        };
+        let [record] = &server.store.history().unwrap()[..] else {
+            panic!("the vote wasn't archived");
+        };
+        assert_eq!(record.id, queued_pr().pull_request.id());
+        assert_eq!(record.outcome, Outcome::Merged);
+        assert_eq!(&record.comment, comment);
+        assert_eq!(record.merge_sha.as_deref(), Some(MERGE_SHA));
+        assert_eq!(record.error, None);
+        assert_eq!(record.finalized_time, server.scheduler.now());
+        assert_eq!(record.policy, "majority");
+    }
+
+    #[tokio::test]
+    async fn refusals_from_github_are_archived() {
+        let refusing = || FakeGitHub {
+            refusal: Some("Pull Request is not mergeable".to_string()),
+            ..FakeGitHub::default()
+        };
+        for (left, right, outcome) in [(2, 3, Outcome::MergeFailed), (3, 2, Outcome::Closed)] {
+            let (server, clock, _github) =
+                finalizing_server_with(pr_with_votes("", left, right), refusing());
+            clock.advance(60 * 24).await;
+            let [record] = &server.store.history().unwrap()[..] else {
+                panic!("the vote wasn't archived");
+            };
+            assert_eq!(record.outcome, outcome);
+            assert_eq!(record.merge_sha, None);
+            assert_eq!(
+                record.error.as_deref(),
+                Some("Pull Request is not mergeable")
+            );
+        }
+    }
+
+    #[test]
+    fn missing_the_quorum_counts_the_extensions() {
+        assert_eq!(
+            missed_quorum(1.0, 3, 1),
+            "Only 1 of the 3 votes needed were cast, even after an extension."
+        );
+    }
+
+    #[tokio::test]
+    async fn a_second_vote_from_the_same_account_replaces_the_first() {
+        let server = Server {
+            auth: Auth::new(
+                Some(auth::tests::fake_provider().await),
+                Signer::new(b"test-session-secret"),
+            ),
+            ..test_server()
+        };
+        let pr = PullRequestInfo {
+            votes: BTreeMap::new(),
+            ..queued_pr()
+        };
+        let id = pr.pull_request.id();
+        server.store.insert(pr).unwrap();
+
+        let session = auth::tests::with_cookie(&auth::tests::sign_in(&server.auth).await);
+        let voter = server.auth.voter(&session).unwrap();
+        server
+            .vote_on_pr(&voter, id.clone(), Direction::Right)
+            .unwrap();
+        // Signing in again is still the same account
+        let session = auth::tests::with_cookie(&auth::tests::sign_in(&server.auth).await);
+        let voter = server.auth.voter(&session).unwrap();
+        server
+            .vote_on_pr(&voter, id.clone(), Direction::Left)
+            .unwrap();
+
+        let pr = server.store.get(&id).unwrap().unwrap();
+        assert_eq!(
+            pr.votes,
+            BTreeMap::from([("github:583231".to_string(), Direction::Left)])
+        );
+        assert_eq!((pr.left_votes(), pr.right_votes()), (1, 0));
+    }
+
+    #[test]
+    fn votes_on_an_old_revision_are_refused() {
+        let server = test_server();
+        let pr = queued_pr();
+        let id = pr.pull_request.id();
+        server.store.insert(pr).unwrap();
+
+        let stale = PrId {
+            revision: new_revision().key.unwrap(),
+            ..id.clone()
+        };
+        let error = server
+            .vote_on_pr(&user(1), stale, Direction::Left)
+            .unwrap_err();
+        assert_eq!(error.code, ApiErrorCode::StaleRevision);
+        assert_eq!(server.store.get(&id).unwrap().unwrap().left_votes(), 2);
+    }
+
+    #[test]
+    fn votes_can_be_changed_and_retracted_with_a_history() {
+        let server = test_server();
+        let pr = PullRequestInfo {
+            votes: BTreeMap::new(),
+            ..queued_pr()
+        };
+        let id = pr.pull_request.id();
+        server.store.insert(pr).unwrap();
+        let voter = device("abc");
+
+        server
+            .vote_on_pr(&voter, id.clone(), Direction::Right)
+            .unwrap();
+        server
+            .vote_on_pr(&voter, id.clone(), Direction::Left)
+            .unwrap();
+        // Voting the same way again changes nothing
+        server
+            .vote_on_pr(&voter, id.clone(), Direction::Left)
+            .unwrap();
+        server.retract_vote(&voter, id.clone()).unwrap();
+        let error = server.retract_vote(&voter, id.clone()).unwrap_err();
+        assert_eq!(error.code, ApiErrorCode::NotFound);
+
+        let pr = server.store.get(&id).unwrap().unwrap();
+        assert!(pr.votes.is_empty());
+        let changes: Vec<_> = pr
+            .history
+            .iter()
+            .map(|change| (change.from, change.to))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                (None, Some(Direction::Right)),
+                (Some(Direction::Right), Some(Direction::Left)),
+                (Some(Direction::Left), None),
+            ]
+        );
+        assert!(pr
+            .history
+            .iter()
+            .all(|change| change.revision == id.revision));
+    }
+
+    #[tokio::test]
+    async fn following_the_queue_is_capped() {
+        let server = test_server();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let followers: Vec<_> = (1..MAX_EVENT_FOLLOWERS)
+            .map(|_| server.events.subscribe())
+            .collect();
+        let last = server.events_handler(addr, HeaderMap::new());
+        assert_eq!(last.status(), StatusCode::OK);
+
+        let refused = server.events_handler(addr, HeaderMap::new());
+        assert_eq!(refused.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // A follower that leaves makes room for another
+        drop(last);
+        drop(followers);
+        let response = server.events_handler(addr, HeaderMap::new());
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn skipped_prs_are_not_handed_out_again() {
+        let server = test_server();
+        let pr = queued_pr();
+        let id = pr.pull_request.id();
+        server.store.insert(pr).unwrap();
+
+        server
+            .vote_on_pr(&device("abc"), id.clone(), Direction::Skip)
+            .unwrap();
+        assert_eq!(
+            server
+                .select_pr(Some("device:abc"), &PrFilter::default())
+                .unwrap(),
+            None
+        );
+        // The picked PR comes with its diff
+        assert_eq!(
+            server
+                .select_pr(Some("device:def"), &PrFilter::default())
+                .unwrap(),
+            Some(queued_pr().pull_request)
+        );
+        assert_eq!(server.store.get(&id).unwrap().unwrap().abstentions(), 1);
+    }
+
+    #[test]
+    fn only_prs_matching_the_filter_are_handed_out() {
+        let server = test_server();
+        server.store.insert(queued_pr()).unwrap();
+        let filter = |extensions: &str| PrFilter {
+            extensions: Some(extensions.to_string()),
+            ..PrFilter::default()
+        };
+
+        assert_eq!(server.select_pr(None, &filter("rs")).unwrap(), None);
+        assert_eq!(
+            server.select_pr(None, &filter("md")).unwrap(),
+            Some(queued_pr().pull_request)
+        );
+    }
+
+    #[tokio::test]
+    async fn abstentions_dont_count_toward_the_quorum() {
+        let mut pr = pr_with_votes("quorum = 2\nmax_extensions = 0", 0, 1);
+        pr.votes.insert("github:7".to_string(), Direction::Skip);
+        pr.votes.insert("github:8".to_string(), Direction::Skip);
+        let (_server, clock, github) = finalizing_server(pr);
+
+        clock.advance(60 * 24).await;
+        assert_eq!(
+            github.take(),
+            [Action::Deny(
+                "Only 1 of the 2 votes needed were cast. The repo closes PRs without a quorum, so this PR is denied! 1 accepted, 0 denied, 2 abstained.".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn only_votes_that_change_something_are_published() {
+        let server = test_server();
+        let pr = queued_pr();
+        let id = pr.pull_request.id();
+        server.store.insert(pr).unwrap();
+        let mut events = server.events.subscribe();
+
+        server
+            .vote_on_pr(&device("abc"), id.clone(), Direction::Right)
+            .unwrap();
+        server
+            .vote_on_pr(&device("abc"), id.clone(), Direction::Right)
+            .unwrap();
+        let stale = PrId {
+            revision: "0000000".to_string(),
+            ..id.clone()
+        };
+        let error = server
+            .vote_on_pr(&device("abc"), stale, Direction::Left)
+            .unwrap_err();
+        assert_eq!(error.code, ApiErrorCode::StaleRevision);
+
+        assert!(matches!(events.try_recv(), Ok(QueueEvent::Voted { .. })));
+        assert!(events.try_recv().is_err());
+        assert_eq!(server.store.get(&id).unwrap().unwrap().history.len(), 1);
+    }
+
+    #[test]
+    fn votes_cant_be_retracted_after_the_deadline() {
+        let server = test_server();
+        // The PR stays queued while it is being finalized
+        let closed = PullRequestInfo {
+            deadline: server.scheduler.now(),
+            ..queued_pr()
+        };
+        let id = closed.pull_request.id();
+        server.store.insert(closed).unwrap();
+
+        let error = server.retract_vote(&user(1), id.clone()).unwrap_err();
+        assert_eq!(error.code, ApiErrorCode::VoteClosed);
+        assert_eq!(server.store.get(&id).unwrap().unwrap().left_votes(), 2);
+    }
+
+    #[test]
+    fn signing_in_moves_the_votes_of_the_device_to_the_account() {
+        let server = test_server();
+        let voted_once = PullRequestInfo {
+            votes: BTreeMap::from([("device:abc".to_string(), Direction::Right)]),
+            ..queued_pr()
+        };
+        let mut voted_twice = PullRequestInfo {
+            votes: BTreeMap::from([
+                ("device:abc".to_string(), Direction::Right),
+                ("github:583231".to_string(), Direction::Left),
+            ]),
+            ..queued_pr()
+        };
+        voted_twice.pull_request.pr_number = 8;
+        let (once, twice) = (voted_once.pull_request.id(), voted_twice.pull_request.id());
+        server.store.insert(voted_once).unwrap();
+        server.store.insert(voted_twice).unwrap();
+
+        server
+            .claim_device_votes(
+                "device:abc",
+                &User {
+                    id: 583231,
+                    login: "octocat".to_string(),
+                },
+            )
+            .unwrap();
+
+        let votes = |id: &PrId| server.store.get(id).unwrap().unwrap().votes;
+        assert_eq!(
+            votes(&once),
+            BTreeMap::from([("github:583231".to_string(), Direction::Right)])
+        );
+        // The account already voted, so its own vote counts
+        assert_eq!(
+            votes(&twice),
+            BTreeMap::from([("github:583231".to_string(), Direction::Left)])
+        );
+        let pr = server.store.get(&once).unwrap().unwrap();
+        assert_eq!(pr.login("github:583231"), Some("octocat"));
+    }
+
+    #[tokio::test]
+    async fn votes_need_a_device_token_or_a_session() {
+        let server = test_server();
+        let pr = queued_pr();
+        let id = pr.pull_request.id();
+        server.store.insert(pr).unwrap();
+        assert_eq!(
+            server
+                .vote_handler(CLIENT, HeaderMap::new(), vote(&id, Direction::Right))
+                .await
+                .status(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        let device = auth::tests::with_cookie(&server.auth.issue_device());
+        assert_eq!(
+            server
+                .vote_handler(CLIENT, device, vote(&id, Direction::Right))
+                .await
+                .status(),
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(server.store.get(&id).unwrap().unwrap().right_votes(), 4);
+    }
+
+    #[tokio::test]
+    async fn anonymous_votes_count_toward_the_quorum_with_their_weight() {
+        let mut pr = pr_with_votes("quorum = 2\nanonymous_weight = 0.25", 1, 0);
+        for device in 0..3 {
+            pr.votes
+                .insert(format!("device:{device}"), Direction::Right);
+        }
+        let (_server, clock, github) = finalizing_server(pr);
+        clock.advance(60 * 24).await;
+        assert_eq!(
+            github.take(),
+            [Action::Deny(
+                "Only 1.75 of the 2 votes needed were cast. The repo closes PRs without a quorum, so this PR is denied! 3 accepted, 1 denied, 0 abstained.".to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn votes_are_refused_with_the_reason() {
+        let server = test_server();
+        let device = auth::tests::with_cookie(&server.auth.issue_device());
+        let closed = PullRequestInfo {
+            deadline: server.scheduler.now(),
+            ..queued_pr()
+        };
+        let id = closed.pull_request.id();
+
+        let error = server.vote_on_pr(&user(1), id.clone(), Direction::Right);
+        assert_eq!(error.unwrap_err().code, ApiErrorCode::NotFound);
+
+        server.store.insert(closed).unwrap();
+        let response = server
+            .vote_handler(CLIENT, device, vote(&id, Direction::Right))
+            .await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let error: ApiError = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error.code, ApiErrorCode::VoteClosed);
+    }
+
+    #[test]
+    fn an_empty_queue_has_no_content() {
+        let response =
+            test_server().pr_handler(CLIENT, HeaderMap::new(), Ok(Query(PrFilter::default())));
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[test]
+    fn hands_out_a_limited_number_of_device_tokens_per_ip() {
+        let mut limits = RateLimits::default();
+        limits.device = RateLimiter::new(Limit {
+            burst: 3.0,
+            per_minute: 0.1,
+        });
+        let server = Server {
+            limits,
+            ..test_server()
+        };
+        server.store.insert(queued_pr()).unwrap();
+        let issued = (0..5)
+            .filter(|_| {
+                let response =
+                    server.pr_handler(CLIENT, HeaderMap::new(), Ok(Query(PrFilter::default())));
+                assert_eq!(response.status(), StatusCode::OK);
+                response.headers().contains_key(SET_COOKIE)
+            })
+            .count();
+        assert_eq!(issued, 3);
+
+        // Browsers that kept their token don't need a new one
+        let device = auth::tests::with_cookie(&server.auth.issue_device());
+        let response = server.pr_handler(CLIENT, device, Ok(Query(PrFilter::default())));
+        assert!(!response.headers().contains_key(SET_COOKIE));
+    }
+
+    #[tokio::test]
+    async fn voting_too_quickly_is_rate_limited() {
+        let mut limits = RateLimits::default();
+        limits.vote = RateLimiter::new(Limit {
+            burst: 2.0,
+            per_minute: 30.0,
+        });
+        let server = Server {
+            limits,
+            ..test_server()
+        };
+        let pr = queued_pr();
+        let id = pr.pull_request.id();
+        server.store.insert(pr).unwrap();
+        let device = auth::tests::with_cookie(&server.auth.issue_device());
+        let mut statuses = Vec::new();
+        for _ in 0..3 {
+            let response = server
+                .vote_handler(CLIENT, device.clone(), vote(&id, Direction::Right))
+                .await;
+            statuses.push(response.status());
+        }
+        assert_eq!(
+            statuses,
+            [
+                StatusCode::NO_CONTENT,
+                StatusCode::NO_CONTENT,
+                StatusCode::TOO_MANY_REQUESTS
+            ]
+        );
+    }
+
+    /// A store that fails every read and write, like one on a disk that filled up
+    struct BrokenStore;
+
+    fn broken() -> std::io::Error {
+        std::io::Error::other("No space left on device")
+    }
+
+    impl Store for BrokenStore {
+        fn get(&self, _: &PrId) -> std::io::Result<Option<PullRequestInfo>> {
+            Err(broken())
+        }
+
+        fn all(&self) -> std::io::Result<Vec<PullRequestInfo>> {
+            Err(broken())
+        }
+
+        fn all_without_diffs(&self) -> std::io::Result<Vec<PullRequestInfo>> {
+            Err(broken())
+        }
+
+        fn insert(&self, _: PullRequestInfo) -> std::io::Result<()> {
+            Err(broken())
+        }
+
+        fn update(
+            &self,
+            _: &PrId,
+            _: &mut dyn FnMut(&mut PullRequestInfo),
+        ) -> std::io::Result<Option<PullRequestInfo>> {
+            Err(broken())
+        }
+
+        fn remove(&self, _: &PrId) -> std::io::Result<Option<PullRequestInfo>> {
+            Err(broken())
+        }
+
+        fn record_resolved(&self, _: ResolvedOutsideVote) -> std::io::Result<()> {
+            Err(broken())
+        }
+
+        fn resolved(&self) -> std::io::Result<Vec<ResolvedOutsideVote>> {
+            Err(broken())
+        }
+
+        fn record_history(&self, _: HistoryRecord) -> std::io::Result<()> {
+            Err(broken())
+        }
+
+        fn history(&self) -> std::io::Result<Vec<HistoryRecord>> {
+            Err(broken())
+        }
+
+        fn export(&self) -> std::io::Result<State> {
+            Err(broken())
+        }
+
+        fn import(&self, _: State) -> std::io::Result<()> {
+            Err(broken())
+        }
+    }
+
+    #[tokio::test]
+    async fn store_failures_are_returned() {
+        let server = Server {
+            store: Arc::new(BrokenStore),
+            ..test_server()
+        };
+        let id = queued_pr().pull_request.id();
+        let error = server.vote_on_pr(&user(1), id.clone(), Direction::Right);
+        assert_eq!(error.unwrap_err().code, ApiErrorCode::Internal);
+        let response = server.pr_handler(CLIENT, HeaderMap::new(), Ok(Query(PrFilter::default())));
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(server.resolve_outside_vote(&id, false).is_err());
+    }
+
+    #[tokio::test]
+    async fn webhook_with_a_valid_signature_is_handled() {
+        let body = Bytes::from_static(include_bytes!("fixtures/ping.json"));
+        let headers =
+            ping_headers("sha256=f6191d8322cfaf5a123b35c0df620bd7b1eb51129cf0a73f167681fb9df58e50");
+        let status = test_server().webhook_handler(headers, body).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn webhook_with_a_mismatched_signature_is_unauthorized() {
+        let body = Bytes::from_static(include_bytes!("fixtures/ping.json"));
+        // Signed with a different secret
+        let headers =
+            ping_headers("sha256=4333f7bc5de6eed0c46afc1fbebb4cf416f98b01b61ad7f16d48b76547f2bd17");
+        let status = test_server().webhook_handler(headers, body).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn webhook_with_an_unknown_pull_request_action_is_acknowledged() {
+        let body = Bytes::from_static(br#"{"action":"auto_merge_enabled","number":7}"#);
+        let mut mac = Hmac::<Sha256>::new_from_slice(WEBHOOK_SECRET).unwrap();
+        mac.update(&body);
+        let signature = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+        let mut headers = HeaderMap::new();
+        headers.insert(webhook::EVENT_HEADER, "pull_request".parse().unwrap());
+        headers.insert(
+            webhook::SIGNATURE_HEADER,
+            format!("sha256={}", signature).parse().unwrap(),
+        );
+        let status = test_server().webhook_handler(headers, body).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn webhook_without_a_signature_is_unauthorized() {
+        let body = Bytes::from_static(include_bytes!("fixtures/ping.json"));
+        let mut headers = HeaderMap::new();
+        headers.insert(webhook::EVENT_HEADER, "ping".parse().unwrap());
+        let status = test_server().webhook_handler(headers, body).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
+
+/// The PR a webhook is about, at the head SHA it was sent for
+fn pr_id_from_payload(payload: &PullRequestEventPayload) -> PrId {
+    let repo = payload.pull_request.base.repo.as_ref().unwrap();
+    PrId {
+        owner: repo.owner.as_ref().unwrap().login.clone(),
+        repo: repo.name.clone(),
+        number: payload.pull_request.number,
+        revision: payload.pull_request.head.sha.clone(),
+    }
+}
+
+/// The archive entry for a PR finalized at `time`, before the bot acted on it
+fn history_record(
+    pr: &PullRequestInfo,
+    comment: &str,
+    outcome: Outcome,
+    time: chrono::DateTime<chrono::Utc>,
+) -> HistoryRecord {
+    HistoryRecord {
+        id: pr.pull_request.id(),
+        title: pr.pull_request.title.clone(),
+        author: pr.pull_request.author.clone(),
+        outcome,
+        tally: queue::tally(pr),
+        policy: pr.config.policy.to_string(),
+        comment: comment.to_string(),
+        merge_sha: None,
+        error: None,
+        finalized_time: time,
+        lottery: None,
+        ballots: pr
+            .votes
+            .iter()
+            .filter_map(|(voter, &direction)| {
+                Some(Ballot {
+                    voter: voter.clone(),
+                    login: pr.login(voter)?.to_string(),
+                    direction,
+                })
+            })
+            .collect(),
     }
 }
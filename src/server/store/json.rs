@@ -0,0 +1,719 @@
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, RwLock},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use super::{
+    import_legacy,
+    migrations::{self, QuarantinedRecord, Versioned},
+    read_json, State, Store,
+};
+use crate::{
+    server::server::{PullRequestInfo, ResolvedOutsideVote, VoteChange},
+    Direction, HistoryRecord, PrId,
+};
+
+/// How many entries the journal can grow to before it is folded into the snapshot
+const COMPACT_AFTER_ENTRIES: usize = 1000;
+
+/// A single change to the state of the server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum JournalEntry {
+    /// A PR was added to the queue or replaced with a newer revision
+    Upsert { pr: PullRequestInfo },
+    /// A PR left the queue
+    Remove { id: PrId },
+    /// The votes on a queued PR changed. This stores every vote so replaying it twice is harmless
+    Votes {
+        id: PrId,
+        votes: BTreeMap<String, Direction>,
+        /// The change to add to the history. Entries written before the history was kept have none
+        #[serde(default)]
+        change: Option<VoteChange>,
+    },
+    /// A PR was merged or closed on GitHub before the vote finished
+    Resolved { resolved: ResolvedOutsideVote },
+    /// The vote on a PR ended
+    Finalized { record: HistoryRecord },
+}
+
+impl State {
+    fn apply(&mut self, entry: JournalEntry) {
+        match entry {
+            JournalEntry::Upsert { pr } => {
+                self.prs.insert(pr.pull_request.id().key(), pr);
+            }
+            JournalEntry::Remove { id } => {
+                self.prs.remove(&id.key());
+            }
+            JournalEntry::Votes { id, votes, change } => {
+                if let Some(pr) = self.prs.get_mut(&id.key()) {
+                    pr.votes = votes;
+                    if let Some(change) = change.filter(|change| !pr.history.contains(change)) {
+                        pr.history.push(change);
+                    }
+                }
+            }
+            JournalEntry::Resolved { resolved } => {
+                // The same entry can be replayed if we crashed in the middle of a compaction
+                let duplicate = self.resolved_outside_vote.iter().any(|existing| {
+                    existing.pull_request.diff_url == resolved.pull_request.diff_url
+                        && existing.resolved_time == resolved.resolved_time
+                });
+                if !duplicate {
+                    self.resolved_outside_vote.push(resolved);
+                }
+            }
+            JournalEntry::Finalized { record } => {
+                let duplicate = self.history.iter().any(|existing| {
+                    existing.id == record.id && existing.finalized_time == record.finalized_time
+                });
+                if !duplicate {
+                    self.history.push(record);
+                }
+            }
+        }
+    }
+}
+
+/// An append-only log of every change, on top of a snapshot of the state
+///
+/// Every entry is flushed to disk before `append` returns, so a crash loses at most the change that was being
+/// written. Once the log grows large enough it is folded into a new snapshot.
+struct Journal {
+    dir: PathBuf,
+    log: Mutex<JournalLog>,
+}
+
+struct JournalLog {
+    file: File,
+    entries: usize,
+}
+
+impl Journal {
+    /// Open the journal in `dir` and rebuild the state from the snapshot and log
+    ///
+    /// If there is no snapshot yet, the state is imported from the `prs.json` file older versions of the server
+    /// wrote on shutdown. A snapshot that isn't JSON at all is moved to the `quarantine` directory, and the state
+    /// starts over from the journal. Records that can't be upgraded are set aside in `quarantine.jsonl`.
+    fn open(dir: &Path) -> std::io::Result<(Self, State)> {
+        let dir = dir.to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+
+        let mut quarantine = Vec::new();
+        let snapshot_path = dir.join("state.json");
+        let (mut state, imported) = if snapshot_path.exists() {
+            match read_json(&snapshot_path)? {
+                Some(stored) => (migrations::load_state(stored, &mut quarantine), false),
+                None => (State::default(), true),
+            }
+        } else {
+            (import_legacy(&dir, &mut quarantine)?, true)
+        };
+
+        let mut entries = 0;
+        let log_path = dir.join("journal.jsonl");
+        if log_path.exists() {
+            for line in BufReader::new(File::open(&log_path)?).lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str(&line) {
+                    Ok(stored) => {
+                        if let Some(entry) = load_entry(stored, &mut quarantine) {
+                            state.apply(entry);
+                        }
+                        entries += 1;
+                    }
+                    // Only the last line can be torn by a crash, and it never finished being written
+                    Err(e) => println!("Skipping unreadable journal entry: {}", e),
+                }
+            }
+        }
+        write_quarantine(&dir, &quarantine)?;
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)?;
+        let journal = Self {
+            dir,
+            log: Mutex::new(JournalLog { file, entries }),
+        };
+        // Rewrite everything in the current version right away, so old records are only migrated once
+        if imported || !quarantine.is_empty() || entries > 0 {
+            journal.compact(&state)?;
+        }
+
+        Ok((journal, state))
+    }
+
+    /// Durably record a change
+    fn append(&self, entry: &JournalEntry) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(&Versioned::current(entry))?;
+        line.push(b'\n');
+
+        let mut log = self.log.lock().unwrap();
+        let len = log.file.metadata()?.len();
+        if let Err(e) = log.file.write_all(&line).and_then(|_| log.file.sync_data()) {
+            // Cut off what made it to disk, so the torn entry doesn't end up in the middle of the log
+            if let Err(truncate_error) = log.file.set_len(len) {
+                println!("Failed to truncate the journal: {}", truncate_error);
+            }
+            return Err(e);
+        }
+        log.entries += 1;
+        Ok(())
+    }
+
+    fn needs_compaction(&self) -> bool {
+        self.log.lock().unwrap().entries >= COMPACT_AFTER_ENTRIES
+    }
+
+    /// Replace the snapshot with `state` and empty the log
+    ///
+    /// `state` must include every entry appended so far.
+    fn compact(&self, state: &State) -> std::io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+
+        // Write the new snapshot next to the old one and swap it in, so there is always a complete snapshot on disk
+        let tmp_path = self.dir.join("state.json.tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&serde_json::to_vec(&Versioned::current(state))?)?;
+        tmp.sync_all()?;
+        std::fs::rename(&tmp_path, self.dir.join("state.json"))?;
+
+        log.file.set_len(0)?;
+        log.file.sync_all()?;
+        log.entries = 0;
+        Ok(())
+    }
+}
+
+/// Keeps the state in memory and every change in a [`Journal`] of JSON lines in a directory
+pub struct JsonStore {
+    state: RwLock<State>,
+    journal: Journal,
+}
+
+impl JsonStore {
+    pub fn open(dir: &Path) -> std::io::Result<Self> {
+        let (journal, state) = Journal::open(dir)?;
+        Ok(Self {
+            state: RwLock::new(state),
+            journal,
+        })
+    }
+
+    /// Record a change. Must be called while holding the state write lock so compaction can never miss an entry
+    fn persist(&self, entry: &JournalEntry) -> std::io::Result<()> {
+        self.journal.append(entry).inspect_err(|e| {
+            println!("Failed to write journal entry {:?}: {}", entry, e);
+        })
+    }
+
+    /// Fold the journal into a new snapshot once it gets long. The change is already durable in the journal, so
+    /// failing to compact only means the journal keeps growing
+    fn maybe_compact(&self) {
+        if self.journal.needs_compaction() {
+            if let Err(e) = self.flush() {
+                println!("Failed to compact the journal: {}", e);
+            }
+        }
+    }
+}
+
+impl Store for JsonStore {
+    fn get(&self, id: &PrId) -> std::io::Result<Option<PullRequestInfo>> {
+        Ok(self.state.read().unwrap().prs.get(&id.key()).cloned())
+    }
+
+    fn all(&self) -> std::io::Result<Vec<PullRequestInfo>> {
+        Ok(self.state.read().unwrap().prs.values().cloned().collect())
+    }
+
+    fn all_without_diffs(&self) -> std::io::Result<Vec<PullRequestInfo>> {
+        let state = self.state.read().unwrap();
+        Ok(state
+            .prs
+            .values()
+            .map(PullRequestInfo::without_diff)
+            .collect())
+    }
+
+    fn insert(&self, pr: PullRequestInfo) -> std::io::Result<()> {
+        {
+            let mut state = self.state.write().unwrap();
+            self.persist(&JournalEntry::Upsert { pr: pr.clone() })?;
+            state.prs.insert(pr.pull_request.id().key(), pr);
+        }
+        self.maybe_compact();
+        Ok(())
+    }
+
+    fn update(
+        &self,
+        id: &PrId,
+        f: &mut dyn FnMut(&mut PullRequestInfo),
+    ) -> std::io::Result<Option<PullRequestInfo>> {
+        let updated = {
+            let mut state = self.state.write().unwrap();
+            let Some(pr) = state.prs.get_mut(&id.key()) else {
+                return Ok(None);
+            };
+            let before = pr.clone();
+            let mut updated = before.clone();
+            f(&mut updated);
+            if updated == before {
+                return Ok(Some(updated));
+            }
+
+            // Votes are by far the most common change, so don't write the whole diff out for each one
+            let changes = updated
+                .history
+                .get(before.history.len()..)
+                .unwrap_or_default();
+            let only_votes_changed = changes.len() <= 1
+                && PullRequestInfo {
+                    votes: updated.votes.clone(),
+                    history: updated.history.clone(),
+                    ..before
+                } == updated;
+            let entry = if only_votes_changed {
+                JournalEntry::Votes {
+                    id: updated.pull_request.id(),
+                    votes: updated.votes.clone(),
+                    change: changes.first().cloned(),
+                }
+            } else {
+                JournalEntry::Upsert {
+                    pr: updated.clone(),
+                }
+            };
+            // Only change the PR in memory once the change is on disk
+            self.persist(&entry)?;
+            *pr = updated.clone();
+            updated
+        };
+        self.maybe_compact();
+        Ok(Some(updated))
+    }
+
+    fn remove(&self, id: &PrId) -> std::io::Result<Option<PullRequestInfo>> {
+        let removed = {
+            let mut state = self.state.write().unwrap();
+            let Some(pr) = state.prs.get(&id.key()) else {
+                return Ok(None);
+            };
+            self.persist(&JournalEntry::Remove {
+                id: pr.pull_request.id(),
+            })?;
+            state.prs.remove(&id.key())
+        };
+        self.maybe_compact();
+        Ok(removed)
+    }
+
+    fn record_resolved(&self, resolved: ResolvedOutsideVote) -> std::io::Result<()> {
+        {
+            let mut state = self.state.write().unwrap();
+            self.persist(&JournalEntry::Resolved {
+                resolved: resolved.clone(),
+            })?;
+            state.resolved_outside_vote.push(resolved);
+        }
+        self.maybe_compact();
+        Ok(())
+    }
+
+    fn resolved(&self) -> std::io::Result<Vec<ResolvedOutsideVote>> {
+        Ok(self.state.read().unwrap().resolved_outside_vote.clone())
+    }
+
+    fn record_history(&self, record: HistoryRecord) -> std::io::Result<()> {
+        {
+            let mut state = self.state.write().unwrap();
+            self.persist(&JournalEntry::Finalized {
+                record: record.clone(),
+            })?;
+            state.history.push(record);
+        }
+        self.maybe_compact();
+        Ok(())
+    }
+
+    fn history(&self) -> std::io::Result<Vec<HistoryRecord>> {
+        Ok(self.state.read().unwrap().history.clone())
+    }
+
+    fn export(&self) -> std::io::Result<State> {
+        Ok(self.state.read().unwrap().clone())
+    }
+
+    fn import(&self, imported: State) -> std::io::Result<()> {
+        let mut state = self.state.write().unwrap();
+        // Write the snapshot first, so a failed import leaves the store as it was
+        self.journal.compact(&imported)?;
+        *state = imported;
+        Ok(())
+    }
+
+    fn flush(&self) -> std::io::Result<()> {
+        let state = self.state.read().unwrap();
+        self.journal.compact(&state)
+    }
+}
+
+/// Upgrade a journal entry and the records inside it to the current version
+fn load_entry(stored: Value, quarantine: &mut Vec<QuarantinedRecord>) -> Option<JournalEntry> {
+    let stored = Versioned::from_stored(stored);
+    let Versioned { version, mut data } = stored.clone();
+    let op = data.get("op").and_then(Value::as_str).unwrap_or_default();
+    match op {
+        "upsert" => {
+            let pr: PullRequestInfo = migrations::load(
+                Versioned {
+                    version,
+                    data: data["pr"].take(),
+                },
+                quarantine,
+            )?;
+            data["pr"] = serde_json::to_value(pr).unwrap();
+        }
+        "resolved" => {
+            let resolved: ResolvedOutsideVote = migrations::load(
+                Versioned {
+                    version,
+                    data: data["resolved"].take(),
+                },
+                quarantine,
+            )?;
+            data["resolved"] = serde_json::to_value(resolved).unwrap();
+        }
+        "finalized" => {
+            let record: HistoryRecord = migrations::load(
+                Versioned {
+                    version,
+                    data: data["record"].take(),
+                },
+                quarantine,
+            )?;
+            data["record"] = serde_json::to_value(record).unwrap();
+        }
+        // Version 1 entries named the PR by its diff url
+        "remove" if version < 2 => {
+            if let Some(object) = data.as_object_mut() {
+                legacy_id(object);
+            }
+        }
+        // and held the totals instead of the voters
+        "votes" if version < 2 => {
+            if let Some(object) = data.as_object_mut() {
+                legacy_id(object);
+                let votes = migrations::legacy_votes(object);
+                object.insert("votes".to_string(), votes);
+            }
+        }
+        _ => {}
+    }
+    match serde_json::from_value(data) {
+        Ok(entry) => Some(entry),
+        Err(e) => {
+            quarantine.push(QuarantinedRecord::new(
+                "journal_entry",
+                stored,
+                e.to_string(),
+            ));
+            None
+        }
+    }
+}
+
+/// Replace the diff url of a version 1 entry with the id of its PR. Entries with an unreadable url are left as they
+/// are, so they end up in the quarantine
+fn legacy_id(entry: &mut Map<String, Value>) {
+    let id = entry
+        .get("diff_url")
+        .and_then(Value::as_str)
+        .and_then(migrations::pr_id_from_diff_url);
+    if let Some(id) = id {
+        entry.remove("diff_url");
+        entry.insert("id".to_string(), serde_json::to_value(id).unwrap());
+    }
+}
+
+/// Set unreadable records aside in `quarantine.jsonl`
+fn write_quarantine(dir: &Path, quarantine: &[QuarantinedRecord]) -> std::io::Result<()> {
+    if quarantine.is_empty() {
+        return Ok(());
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join("quarantine.jsonl"))?;
+    for record in quarantine {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+    }
+    file.sync_data()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory for a store, removed again once the test is done
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("gitlucky-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            Self(dir)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn queued_pr() -> PullRequestInfo {
+        serde_json::from_str(include_str!("../fixtures/queued_pr.json")).unwrap()
+    }
+
+    fn finalized_pr() -> HistoryRecord {
+        serde_json::from_str(include_str!("../fixtures/finalized_pr.json")).unwrap()
+    }
+
+    #[test]
+    fn changes_survive_a_restart() {
+        let dir = TestDir::new("json-restart");
+        let pr = queued_pr();
+        let id = pr.pull_request.id();
+        {
+            let store = JsonStore::open(&dir.0).unwrap();
+            store.insert(pr).unwrap();
+            store
+                .update(&id, &mut |pr| {
+                    pr.votes.insert("github:1".to_string(), Direction::Right);
+                    pr.votes.insert("github:6".to_string(), Direction::Left);
+                })
+                .unwrap();
+            store.record_history(finalized_pr()).unwrap();
+        }
+        let store = JsonStore::open(&dir.0).unwrap();
+        let pr = store.get(&id).unwrap().unwrap();
+        assert_eq!((pr.left_votes(), pr.right_votes()), (2, 4));
+        assert_eq!(store.history().unwrap(), vec![finalized_pr()]);
+    }
+
+    #[test]
+    fn a_vote_change_is_journaled_with_the_votes() {
+        let dir = TestDir::new("json-history");
+        let pr = queued_pr();
+        let id = pr.pull_request.id();
+        let change = VoteChange {
+            voter: "github:6".to_string(),
+            login: None,
+            from: None,
+            to: Some(Direction::Left),
+            revision: id.revision.clone(),
+            time: pr.creation_time,
+        };
+        {
+            let store = JsonStore::open(&dir.0).unwrap();
+            store.insert(pr).unwrap();
+            store
+                .update(&id, &mut |pr| {
+                    pr.votes.insert("github:6".to_string(), Direction::Left);
+                    pr.history.push(change.clone());
+                })
+                .unwrap();
+        }
+        let journal = std::fs::read_to_string(dir.0.join("journal.jsonl")).unwrap();
+        assert!(journal.lines().last().unwrap().contains(r#""op":"votes""#));
+
+        let store = JsonStore::open(&dir.0).unwrap();
+        let pr = store.get(&id).unwrap().unwrap();
+        assert_eq!(pr.history, [change]);
+    }
+
+    #[test]
+    fn compaction_keeps_the_state_and_empties_the_log() {
+        let dir = TestDir::new("json-compact");
+        let pr = queued_pr();
+        let id = pr.pull_request.id();
+        {
+            let (journal, mut state) = Journal::open(&dir.0).unwrap();
+            let entry = JournalEntry::Upsert { pr };
+            journal.append(&entry).unwrap();
+            state.apply(entry);
+            journal.compact(&state).unwrap();
+        }
+        assert_eq!(
+            std::fs::metadata(dir.0.join("journal.jsonl"))
+                .unwrap()
+                .len(),
+            0
+        );
+        let (_, state) = Journal::open(&dir.0).unwrap();
+        assert!(state.prs.contains_key(&id.key()));
+    }
+
+    #[test]
+    fn a_torn_last_entry_is_skipped() {
+        let dir = TestDir::new("json-torn");
+        let pr = queued_pr();
+        let id = pr.pull_request.id();
+        {
+            let (journal, _) = Journal::open(&dir.0).unwrap();
+            journal.append(&JournalEntry::Upsert { pr }).unwrap();
+        }
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(dir.0.join("journal.jsonl"))
+            .unwrap();
+        log.write_all(br#"{"op":"remove","diff_u"#).unwrap();
+
+        let (_, state) = Journal::open(&dir.0).unwrap();
+        assert!(state.prs.contains_key(&id.key()));
+    }
+
+    #[test]
+    fn replaying_a_resolved_pr_twice_records_it_once() {
+        let pr = queued_pr();
+        let resolved = ResolvedOutsideVote {
+            left_votes: pr.left_votes(),
+            right_votes: pr.right_votes(),
+            pull_request: pr.pull_request,
+            merged: true,
+            resolved_time: pr.creation_time,
+        };
+        let mut state = State::default();
+        state.apply(JournalEntry::Resolved {
+            resolved: resolved.clone(),
+        });
+        state.apply(JournalEntry::Resolved { resolved });
+        assert_eq!(state.resolved_outside_vote.len(), 1);
+    }
+
+    #[test]
+    fn an_unreadable_snapshot_is_quarantined() {
+        let dir = TestDir::new("json-corrupt-snapshot");
+        std::fs::create_dir_all(&dir.0).unwrap();
+        std::fs::write(dir.0.join("state.json"), "{\"prs\": {").unwrap();
+
+        let store = JsonStore::open(&dir.0).unwrap();
+        assert!(store.all().unwrap().is_empty());
+        let quarantined: Vec<_> = std::fs::read_dir(dir.0.join("quarantine"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert_eq!(quarantined.len(), 1);
+        assert!(quarantined[0].ends_with(".state.json"));
+
+        // A fresh snapshot takes its place, so the next start doesn't quarantine anything
+        store.insert(queued_pr()).unwrap();
+        drop(store);
+        let store = JsonStore::open(&dir.0).unwrap();
+        assert_eq!(store.all().unwrap().len(), 1);
+        assert_eq!(
+            std::fs::read_dir(dir.0.join("quarantine")).unwrap().count(),
+            1
+        );
+    }
+
+    #[test]
+    fn an_unreadable_legacy_file_is_quarantined() {
+        let dir = TestDir::new("json-corrupt-legacy");
+        std::fs::create_dir_all(&dir.0).unwrap();
+        std::fs::write(dir.0.join("prs.json"), "not json").unwrap();
+
+        let store = JsonStore::open(&dir.0).unwrap();
+        assert!(store.all().unwrap().is_empty());
+        assert!(!dir.0.join("prs.json").exists());
+        assert!(dir.0.join("state.json").exists());
+    }
+
+    #[test]
+    fn unversioned_journal_entries_are_upgraded() {
+        let dir = TestDir::new("json-unversioned");
+        std::fs::create_dir_all(&dir.0).unwrap();
+        let mut pr = serde_json::to_value(queued_pr()).unwrap();
+        pr.as_object_mut().unwrap().remove("deadline");
+        let entries = [
+            serde_json::json!({ "op": "upsert", "pr": pr }),
+            serde_json::json!({ "op": "upsert", "pr": { "title": "not a PR" } }),
+            serde_json::json!({
+                "op": "votes",
+                "diff_url": queued_pr().pull_request.diff_url,
+                "left_votes": 1,
+                "right_votes": 4
+            }),
+            serde_json::json!({
+                "op": "remove",
+                "diff_url": "https://github.com/octocat/hello-world/pull/8.diff"
+            }),
+        ];
+        let log: String = entries.iter().map(|entry| format!("{entry}\n")).collect();
+        std::fs::write(dir.0.join("journal.jsonl"), log).unwrap();
+
+        let store = JsonStore::open(&dir.0).unwrap();
+        let prs = store.all().unwrap();
+        assert_eq!(prs.len(), 1);
+        // Unversioned PRs were voted on for a day
+        assert_eq!(
+            prs[0].deadline,
+            prs[0].creation_time + chrono::Duration::days(1)
+        );
+        assert_eq!((prs[0].left_votes(), prs[0].right_votes()), (1, 4));
+        let quarantined = std::fs::read_to_string(dir.0.join("quarantine.jsonl")).unwrap();
+        assert_eq!(quarantined.lines().count(), 1);
+    }
+
+    #[test]
+    fn the_files_of_older_versions_are_imported() {
+        let dir = TestDir::new("json-legacy");
+        std::fs::create_dir_all(&dir.0).unwrap();
+        let pr = queued_pr();
+        let id = pr.pull_request.id();
+        std::fs::write(
+            dir.0.join("prs.json"),
+            serde_json::to_vec(&vec![pr]).unwrap(),
+        )
+        .unwrap();
+
+        let (_, state) = Journal::open(&dir.0).unwrap();
+        assert!(state.prs.contains_key(&id.key()));
+        // The import is written to a snapshot right away
+        assert!(dir.0.join("state.json").exists());
+    }
+
+    #[test]
+    fn a_change_to_nothing_is_not_written() {
+        let dir = TestDir::new("json-unchanged");
+        let store = JsonStore::open(&dir.0).unwrap();
+        let pr = queued_pr();
+        let id = pr.pull_request.id();
+        store.insert(pr.clone()).unwrap();
+
+        // Writes fail, so only an update that writes nothing can succeed
+        store.journal.log.lock().unwrap().file = File::open(dir.0.join("journal.jsonl")).unwrap();
+        let updated = store.update(&id, &mut |pr| {
+            pr.votes.remove("device:abc");
+        });
+        assert_eq!(updated.unwrap(), Some(pr));
+    }
+}
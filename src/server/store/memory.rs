@@ -0,0 +1,86 @@
+use std::sync::RwLock;
+
+use super::{State, Store};
+use crate::{
+    server::server::{PullRequestInfo, ResolvedOutsideVote},
+    HistoryRecord, PrId,
+};
+
+/// Keeps everything in memory. Nothing survives a restart, which makes it handy for tests
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    state: RwLock<State>,
+}
+
+impl Store for MemoryStore {
+    fn get(&self, id: &PrId) -> std::io::Result<Option<PullRequestInfo>> {
+        Ok(self.state.read().unwrap().prs.get(&id.key()).cloned())
+    }
+
+    fn all(&self) -> std::io::Result<Vec<PullRequestInfo>> {
+        Ok(self.state.read().unwrap().prs.values().cloned().collect())
+    }
+
+    fn all_without_diffs(&self) -> std::io::Result<Vec<PullRequestInfo>> {
+        let state = self.state.read().unwrap();
+        Ok(state
+            .prs
+            .values()
+            .map(PullRequestInfo::without_diff)
+            .collect())
+    }
+
+    fn insert(&self, pr: PullRequestInfo) -> std::io::Result<()> {
+        let mut state = self.state.write().unwrap();
+        state.prs.insert(pr.pull_request.id().key(), pr);
+        Ok(())
+    }
+
+    fn update(
+        &self,
+        id: &PrId,
+        f: &mut dyn FnMut(&mut PullRequestInfo),
+    ) -> std::io::Result<Option<PullRequestInfo>> {
+        let mut state = self.state.write().unwrap();
+        let Some(pr) = state.prs.get_mut(&id.key()) else {
+            return Ok(None);
+        };
+        f(pr);
+        Ok(Some(pr.clone()))
+    }
+
+    fn remove(&self, id: &PrId) -> std::io::Result<Option<PullRequestInfo>> {
+        Ok(self.state.write().unwrap().prs.remove(&id.key()))
+    }
+
+    fn record_resolved(&self, resolved: ResolvedOutsideVote) -> std::io::Result<()> {
+        self.state
+            .write()
+            .unwrap()
+            .resolved_outside_vote
+            .push(resolved);
+        Ok(())
+    }
+
+    fn resolved(&self) -> std::io::Result<Vec<ResolvedOutsideVote>> {
+        Ok(self.state.read().unwrap().resolved_outside_vote.clone())
+    }
+
+    fn record_history(&self, record: HistoryRecord) -> std::io::Result<()> {
+        self.state.write().unwrap().history.push(record);
+        Ok(())
+    }
+
+    fn history(&self) -> std::io::Result<Vec<HistoryRecord>> {
+        Ok(self.state.read().unwrap().history.clone())
+    }
+
+    fn export(&self) -> std::io::Result<State> {
+        Ok(self.state.read().unwrap().clone())
+    }
+
+    fn import(&self, state: State) -> std::io::Result<()> {
+        *self.state.write().unwrap() = state;
+        Ok(())
+    }
+}
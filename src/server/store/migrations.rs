@@ -0,0 +1,351 @@
+//! Everything the stores persist is wrapped in a [`Versioned`] envelope. When a record written by an older
+//! version of the server is read back, it is upgraded one version at a time until it matches the types in this
+//! version. Records that can't be upgraded are quarantined instead of taking the server down with them.
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use super::State;
+use crate::{
+    server::server::{PullRequestInfo, ResolvedOutsideVote},
+    Direction, HistoryRecord, PrId,
+};
+
+/// The version of the persisted types in this build of the server
+///
+/// - 1: everything written before records were versioned, like the `prs.json` files saved on shutdown
+/// - 2: the types as they are defined in this version of the server
+///
+/// A release that changes a persisted type bumps this and adds a migration from the version before it.
+pub const CURRENT_VERSION: u32 = 2;
+
+/// Upgrades a record in place from one version to the next
+type Migration = fn(&mut Value) -> Result<(), String>;
+
+/// A persisted value and the version of the types it was written with
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Versioned<T> {
+    pub version: u32,
+    pub data: T,
+}
+
+impl<T> Versioned<T> {
+    pub fn current(data: T) -> Self {
+        Self {
+            version: CURRENT_VERSION,
+            data,
+        }
+    }
+}
+
+impl Versioned<Value> {
+    /// Split a stored value into its version and data. Anything without an envelope predates versioning
+    pub fn from_stored(value: Value) -> Self {
+        match value {
+            Value::Object(mut object)
+                if object.len() == 2
+                    && object.get("version").is_some_and(Value::is_u64)
+                    && object.contains_key("data") =>
+            {
+                Self {
+                    version: object["version"].as_u64().unwrap() as u32,
+                    data: object.remove("data").unwrap(),
+                }
+            }
+            data => Self { version: 1, data },
+        }
+    }
+}
+
+/// A type that is persisted and knows how to upgrade older versions of itself
+pub trait Migrate: DeserializeOwned {
+    /// What kind of record this is, for the quarantine
+    const KIND: &'static str;
+
+    /// `MIGRATIONS[n]` upgrades a record from version `n + 1` to version `n + 2`
+    const MIGRATIONS: &'static [Migration];
+
+    fn upgrade(stored: Versioned<Value>) -> Result<Self, String> {
+        let Versioned { version, mut data } = stored;
+        if version == 0 || version > CURRENT_VERSION {
+            return Err(format!(
+                "unsupported version {version}, this server reads versions up to {CURRENT_VERSION}"
+            ));
+        }
+        for migration in &Self::MIGRATIONS[version as usize - 1..] {
+            migration(&mut data)?;
+        }
+        serde_json::from_value(data).map_err(|e| e.to_string())
+    }
+}
+
+impl Migrate for PullRequestInfo {
+    const KIND: &'static str = "pr";
+    const MIGRATIONS: &'static [Migration] = &[pr_v1_to_v2];
+}
+
+impl Migrate for ResolvedOutsideVote {
+    const KIND: &'static str = "resolved_outside_vote";
+    const MIGRATIONS: &'static [Migration] = &[unchanged];
+}
+
+/// Finalized PRs are only archived since version 2, so there is nothing older to upgrade
+impl Migrate for HistoryRecord {
+    const KIND: &'static str = "history";
+    const MIGRATIONS: &'static [Migration] = &[unchanged];
+}
+
+fn unchanged(_: &mut Value) -> Result<(), String> {
+    Ok(())
+}
+
+/// Version 1 servers finalized every vote a day after the PR was created, counted votes without recording the voters,
+/// didn't keep the votes cast against older revisions or a history of vote changes and didn't read the config of
+/// the repo
+fn pr_v1_to_v2(pr: &mut Value) -> Result<(), String> {
+    const V1_VOTE_MINUTES: i64 = 60 * 24;
+
+    let object = pr.as_object_mut().ok_or("PR is not an object")?;
+    let creation_time: chrono::DateTime<chrono::Utc> = object
+        .get("creation_time")
+        .cloned()
+        .ok_or("PR has no creation_time")
+        .and_then(|time| serde_json::from_value(time).map_err(|_| "creation_time is not a time"))?;
+    if object.get("deadline").is_none_or(Value::is_null) {
+        let deadline = creation_time + chrono::Duration::minutes(V1_VOTE_MINUTES);
+        object.insert("deadline".to_string(), json!(deadline));
+    }
+    if !object.contains_key("votes") {
+        let votes = legacy_votes(object);
+        object.insert("votes".to_string(), votes);
+    }
+    object.entry("stale_left_votes").or_insert_with(|| json!(0));
+    object
+        .entry("stale_right_votes")
+        .or_insert_with(|| json!(0));
+    // Every config field has a default, so an empty table is the default config
+    object.entry("config").or_insert_with(|| json!({}));
+    object.entry("extensions").or_insert_with(|| json!(0));
+    object.entry("lottery_seed").or_insert(Value::Null);
+    object.entry("history").or_insert_with(|| json!([]));
+    Ok(())
+}
+
+/// Version 1 counted votes instead of recording who cast them. Every counted vote becomes a made up voter, so the
+/// tally stays the same
+pub fn legacy_votes(record: &mut Map<String, Value>) -> Value {
+    let mut take = |field: &str| record.remove(field).and_then(|v| v.as_u64()).unwrap_or(0);
+    let (left, right) = (take("left_votes"), take("right_votes"));
+    let left = (0..left).map(|i| (format!("legacy:left:{i}"), json!(Direction::Left)));
+    let right = (0..right).map(|i| (format!("legacy:right:{i}"), json!(Direction::Right)));
+    Value::Object(left.chain(right).collect())
+}
+
+/// The PR a `https://github.com/<owner>/<repo>/pull/<number>.diff` url points at. Version 1 named PRs by their
+/// diff url, which doesn't say which revision was voted on
+pub fn pr_id_from_diff_url(diff_url: &str) -> Option<PrId> {
+    let mut parts = diff_url.split('/').skip(3);
+    let owner = parts.next()?.to_string();
+    let repo = parts.next()?.to_string();
+    parts.next().filter(|&part| part == "pull")?;
+    let number = parts.next()?.strip_suffix(".diff")?.parse().ok()?;
+    Some(PrId {
+        owner,
+        repo,
+        number,
+        revision: String::new(),
+    })
+}
+
+/// A record that couldn't be read. It is set aside with the reason so it can be fixed by hand
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuarantinedRecord {
+    pub kind: String,
+    pub version: u32,
+    pub reason: String,
+    pub record: Value,
+    pub quarantined_time: chrono::DateTime<chrono::Utc>,
+}
+
+impl QuarantinedRecord {
+    pub fn new(kind: &str, stored: Versioned<Value>, reason: String) -> Self {
+        println!(
+            "Quarantining unreadable {} record (version {}): {}",
+            kind, stored.version, reason
+        );
+        Self {
+            kind: kind.to_string(),
+            version: stored.version,
+            reason,
+            record: stored.data,
+            quarantined_time: chrono::Utc::now(),
+        }
+    }
+}
+
+/// Upgrade a single stored record, or quarantine it
+pub fn load<T: Migrate>(
+    stored: Versioned<Value>,
+    quarantine: &mut Vec<QuarantinedRecord>,
+) -> Option<T> {
+    match T::upgrade(stored.clone()) {
+        Ok(record) => Some(record),
+        Err(reason) => {
+            quarantine.push(QuarantinedRecord::new(T::KIND, stored, reason));
+            None
+        }
+    }
+}
+
+/// Upgrade a whole stored [`State`] record by record. The legacy `prs.json` list of PRs is accepted too
+pub fn load_state(stored: Value, quarantine: &mut Vec<QuarantinedRecord>) -> State {
+    let Versioned { version, data } = Versioned::from_stored(stored);
+    let (prs, resolved, history) = match data {
+        Value::Array(prs) => (prs, Vec::new(), Vec::new()),
+        Value::Object(mut object) => {
+            let prs = match object.remove("prs") {
+                Some(Value::Object(prs)) => prs.into_iter().map(|(_, pr)| pr).collect(),
+                Some(Value::Array(prs)) => prs,
+                _ => Vec::new(),
+            };
+            let resolved = match object.remove("resolved_outside_vote") {
+                Some(Value::Array(resolved)) => resolved,
+                _ => Vec::new(),
+            };
+            let history = match object.remove("history") {
+                Some(Value::Array(history)) => history,
+                _ => Vec::new(),
+            };
+            (prs, resolved, history)
+        }
+        other => {
+            quarantine.push(QuarantinedRecord::new(
+                "state",
+                Versioned {
+                    version,
+                    data: other,
+                },
+                "state is not an object or a list of PRs".to_string(),
+            ));
+            (Vec::new(), Vec::new(), Vec::new())
+        }
+    };
+
+    let mut state = State::default();
+    for pr in prs {
+        if let Some(pr) = load::<PullRequestInfo>(Versioned { version, data: pr }, quarantine) {
+            state.prs.insert(pr.pull_request.id().key(), pr);
+        }
+    }
+    state.resolved_outside_vote = resolved
+        .into_iter()
+        .filter_map(|resolved| {
+            load::<ResolvedOutsideVote>(
+                Versioned {
+                    version,
+                    data: resolved,
+                },
+                quarantine,
+            )
+        })
+        .collect();
+    state.history = history
+        .into_iter()
+        .filter_map(|record| {
+            load::<HistoryRecord>(
+                Versioned {
+                    version,
+                    data: record,
+                },
+                quarantine,
+            )
+        })
+        .collect();
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::repo_config::RepoConfig;
+
+    fn version_1_pr() -> Value {
+        json!({
+            "pull_request": {
+                "diff_url": "https://github.com/octocat/hello-world/pull/7.diff",
+                "diff": "",
+                "title": "Say hello to everyone",
+                "additions": 1,
+                "deletions": 1,
+                "changed_files": 1,
+                "author": "octocat",
+                "repo_name": "hello-world",
+                "key": null,
+                "branch_to_merge": "hello",
+                "branch_to_merge_into": "main",
+                "pr_number": 7,
+                "repo_owner": "octocat",
+                "profile_pic_url": ""
+            },
+            "left_votes": 1,
+            "right_votes": 2,
+            "creation_time": "2025-01-01T12:00:00Z"
+        })
+    }
+
+    #[test]
+    fn upgrades_a_version_1_pr() {
+        let pr = PullRequestInfo::upgrade(Versioned::from_stored(version_1_pr())).unwrap();
+        assert_eq!((pr.left_votes(), pr.right_votes()), (1, 2));
+        assert_eq!(pr.deadline.to_rfc3339(), "2025-01-02T12:00:00+00:00");
+        assert_eq!((pr.stale_left_votes, pr.stale_right_votes), (0, 0));
+        assert_eq!(pr.config, RepoConfig::default());
+        assert_eq!(pr.extensions, 0);
+        assert_eq!(pr.lottery_seed, None);
+    }
+
+    #[test]
+    fn current_records_are_read_as_they_are() {
+        let pr = PullRequestInfo::upgrade(Versioned::from_stored(version_1_pr())).unwrap();
+        let stored = serde_json::to_value(Versioned::current(&pr)).unwrap();
+        assert_eq!(
+            PullRequestInfo::upgrade(Versioned::from_stored(stored)),
+            Ok(pr)
+        );
+    }
+
+    #[test]
+    fn records_from_a_newer_server_are_quarantined() {
+        let stored = Versioned {
+            version: CURRENT_VERSION + 1,
+            data: version_1_pr(),
+        };
+        let mut quarantine = Vec::new();
+        assert_eq!(load::<PullRequestInfo>(stored, &mut quarantine), None);
+        assert_eq!(quarantine.len(), 1);
+        assert_eq!(quarantine[0].kind, "pr");
+        assert_eq!(quarantine[0].version, CURRENT_VERSION + 1);
+    }
+
+    #[test]
+    fn a_legacy_list_loads_everything_it_can() {
+        let stored = json!([version_1_pr(), { "title": "not a PR" }]);
+        let mut quarantine = Vec::new();
+        let state = load_state(stored, &mut quarantine);
+        assert_eq!(state.prs.len(), 1);
+        assert!(state.prs.contains_key("octocat/hello-world#7"));
+        assert_eq!(quarantine.len(), 1);
+        assert_eq!(quarantine[0].record, json!({ "title": "not a PR" }));
+    }
+
+    #[test]
+    fn reads_the_pr_of_a_diff_url() {
+        let id = pr_id_from_diff_url("https://github.com/octocat/hello-world/pull/7.diff").unwrap();
+        assert_eq!(id.key(), "octocat/hello-world#7");
+        assert_eq!(id.revision, "");
+        assert_eq!(
+            pr_id_from_diff_url("https://github.com/octocat/hello-world/issues/7"),
+            None
+        );
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::server::{PullRequestInfo, ResolvedOutsideVote};
+use crate::{HistoryRecord, PrId};
+use migrations::QuarantinedRecord;
+
+mod json;
+mod memory;
+pub mod migrations;
+mod sqlite;
+
+pub use json::JsonStore;
+pub use memory::MemoryStore;
+pub use sqlite::SqliteStore;
+
+/// Everything the server persists. This is also the format of exports
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct State {
+    /// Queued PRs by [`PrId::key`]
+    pub prs: HashMap<String, PullRequestInfo>,
+    pub resolved_outside_vote: Vec<ResolvedOutsideVote>,
+    /// Every finalized vote, oldest first
+    #[serde(default)]
+    pub history: Vec<HistoryRecord>,
+}
+
+/// Where the queue, the votes on it and the history of resolved PRs live
+///
+/// Every method is atomic on its own and durable by the time it returns (as durable as the backend is). PRs are
+/// looked up by [`PrId::key`], so the revision of the id doesn't matter.
+pub trait Store: Send + Sync {
+    fn get(&self, id: &PrId) -> std::io::Result<Option<PullRequestInfo>>;
+
+    fn all(&self) -> std::io::Result<Vec<PullRequestInfo>>;
+
+    /// Every queued PR, with only the `diff --git` line of each file left in its diff. The diffs are by far the
+    /// largest part of the queue, and listing or picking PRs only needs to know which files changed
+    fn all_without_diffs(&self) -> std::io::Result<Vec<PullRequestInfo>>;
+
+    /// Add a PR to the queue, replacing any revision of the same PR
+    fn insert(&self, pr: PullRequestInfo) -> std::io::Result<()>;
+
+    /// Change a queued PR in place. Returns the updated PR, or None if it isn't queued. If the change can't be
+    /// saved, the PR is left as it was. If `f` changes nothing, nothing is written
+    fn update(
+        &self,
+        id: &PrId,
+        f: &mut dyn FnMut(&mut PullRequestInfo),
+    ) -> std::io::Result<Option<PullRequestInfo>>;
+
+    fn remove(&self, id: &PrId) -> std::io::Result<Option<PullRequestInfo>>;
+
+    fn record_resolved(&self, resolved: ResolvedOutsideVote) -> std::io::Result<()>;
+
+    fn resolved(&self) -> std::io::Result<Vec<ResolvedOutsideVote>>;
+
+    /// Archive how the vote on a PR ended
+    fn record_history(&self, record: HistoryRecord) -> std::io::Result<()>;
+
+    /// Every finalized vote, oldest first
+    fn history(&self) -> std::io::Result<Vec<HistoryRecord>>;
+
+    /// A copy of everything in the store
+    fn export(&self) -> std::io::Result<State>;
+
+    /// Replace everything in the store with `state`
+    fn import(&self, state: State) -> std::io::Result<()>;
+
+    /// Called on shutdown so backends can tidy up what they wrote
+    fn flush(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Which [`Store`] backend to use, from the `GITLUCKY_STORE` environment variable
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreConfig {
+    Memory,
+    Json { dir: PathBuf },
+    Sqlite { path: PathBuf },
+}
+
+impl StoreConfig {
+    /// Read `GITLUCKY_STORE` (`memory`, `json` or `sqlite`, defaults to `json`) and `GITLUCKY_DATA_DIR`
+    /// (defaults to `data_dir`)
+    pub fn from_env(data_dir: &str) -> Self {
+        let dir = std::env::var("GITLUCKY_DATA_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from(data_dir));
+        match std::env::var("GITLUCKY_STORE").as_deref() {
+            Ok("memory") => Self::Memory,
+            Ok("sqlite") => Self::Sqlite {
+                path: dir.join("gitlucky.sqlite"),
+            },
+            Ok("json") | Err(_) => Self::Json { dir },
+            Ok(other) => panic!("Unknown GITLUCKY_STORE backend: {other}"),
+        }
+    }
+
+    pub fn open(&self) -> std::io::Result<Box<dyn Store>> {
+        Ok(match self {
+            Self::Memory => Box::new(MemoryStore::default()),
+            Self::Json { dir } => Box::new(JsonStore::open(dir)?),
+            Self::Sqlite { path } => Box::new(SqliteStore::open(path)?),
+        })
+    }
+}
+
+/// Write everything in `store` to the file at `path`
+pub fn export_to(store: &dyn Store, path: &str) -> std::io::Result<()> {
+    let state = store.export()?;
+    let file = std::fs::File::create(path)?;
+    serde_json::to_writer_pretty(file, &migrations::Versioned::current(&state))?;
+    println!(
+        "Exported {} PRs, {} resolved PRs and {} finalized PRs to {}",
+        state.prs.len(),
+        state.resolved_outside_vote.len(),
+        state.history.len(),
+        path
+    );
+    Ok(())
+}
+
+/// Replace everything in `store` with the export at `path`
+pub fn import_from(store: &dyn Store, path: &str) -> std::io::Result<()> {
+    let file = std::fs::File::open(path)?;
+    let stored = serde_json::from_reader(std::io::BufReader::new(file))?;
+    let mut quarantine = Vec::new();
+    let state = migrations::load_state(stored, &mut quarantine);
+    if !quarantine.is_empty() {
+        println!(
+            "{} records in {} couldn't be read and were skipped",
+            quarantine.len(),
+            path
+        );
+    }
+    println!(
+        "Importing {} PRs, {} resolved PRs and {} finalized PRs from {}",
+        state.prs.len(),
+        state.resolved_outside_vote.len(),
+        state.history.len(),
+        path
+    );
+    store.import(state)?;
+    store.flush()
+}
+
+/// Read the `prs.json` and `resolved.json` files the server used to write to `dir` on shutdown, so a new store
+/// starts out with them. Records that can't be upgraded are added to `quarantine`
+fn import_legacy(dir: &Path, quarantine: &mut Vec<QuarantinedRecord>) -> std::io::Result<State> {
+    let prs_path = dir.join("prs.json");
+    let stored = if prs_path.exists() {
+        read_json(&prs_path)?
+    } else {
+        None
+    };
+    let mut state = if let Some(stored) = stored {
+        let state = migrations::load_state(stored, quarantine);
+        println!(
+            "Importing {} PRs from {}",
+            state.prs.len(),
+            prs_path.display()
+        );
+        state
+    } else {
+        State::default()
+    };
+
+    let resolved_path = dir.join("resolved.json");
+    if resolved_path.exists() {
+        if let Some(resolved) = read_json(&resolved_path)? {
+            let resolved = serde_json::json!({ "resolved_outside_vote": resolved });
+            state.resolved_outside_vote =
+                migrations::load_state(resolved, quarantine).resolved_outside_vote;
+        }
+    }
+
+    Ok(state)
+}
+
+/// Read a JSON file. A file that can't be parsed is moved to the `quarantine` directory next to it and read as
+/// None, so one corrupt file doesn't keep the server from starting
+fn read_json(path: &Path) -> std::io::Result<Option<Value>> {
+    let e = match serde_json::from_reader(BufReader::new(File::open(path)?)) {
+        Ok(value) => return Ok(Some(value)),
+        Err(e) if e.is_io() => return Err(e.into()),
+        Err(e) => e,
+    };
+    let dir = path.parent().unwrap_or(Path::new(".")).join("quarantine");
+    std::fs::create_dir_all(&dir)?;
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let aside = dir.join(format!("{}.{}", chrono::Utc::now().timestamp(), name));
+    std::fs::rename(path, &aside)?;
+    println!(
+        "{} couldn't be read ({}), so it was moved to {}",
+        path.display(),
+        e,
+        aside.display()
+    );
+    Ok(None)
+}
@@ -0,0 +1,525 @@
+use std::{path::Path, sync::Mutex};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use super::{
+    import_legacy,
+    migrations::{self, Migrate, QuarantinedRecord, Versioned},
+    State, Store,
+};
+use crate::{
+    server::{
+        repo_config,
+        server::{PullRequestInfo, ResolvedOutsideVote},
+    },
+    HistoryRecord, PrId,
+};
+
+/// Keeps everything in an embedded SQLite database. Each PR is stored as a versioned JSON document
+pub struct SqliteStore {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Open the database at `path`. A new database starts out with the `prs.json` and `resolved.json` files older
+    /// versions of the server wrote next to it. Rows written by older versions are upgraded, and the ones that
+    /// can't be are moved to the `quarantine` table
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let dir = path.parent().unwrap_or(Path::new("."));
+        std::fs::create_dir_all(dir)?;
+        let mut connection = Connection::open(path).map_err(std::io::Error::other)?;
+        let created = create_tables(&connection).map_err(std::io::Error::other)?;
+        if created {
+            let mut quarantine = Vec::new();
+            let state = import_legacy(dir, &mut quarantine)?;
+            import_state(&mut connection, &state).map_err(std::io::Error::other)?;
+            for record in &quarantine {
+                put_quarantine(&connection, record).map_err(std::io::Error::other)?;
+            }
+        }
+        migrate_rows::<PullRequestInfo>(&mut connection, "queue", "id")
+            .map_err(std::io::Error::other)?;
+        migrate_rows::<ResolvedOutsideVote>(&mut connection, "resolved_outside_vote", "id")
+            .map_err(std::io::Error::other)?;
+        migrate_rows::<HistoryRecord>(&mut connection, "history", "id")
+            .map_err(std::io::Error::other)?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+}
+
+/// Create the tables if they don't exist yet. Returns whether they were just created
+fn create_tables(connection: &Connection) -> rusqlite::Result<bool> {
+    let exists: bool = connection.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'queue')",
+        [],
+        |row| row.get(0),
+    )?;
+    connection.execute_batch(
+        "PRAGMA journal_mode = WAL;
+        PRAGMA synchronous = FULL;
+        CREATE TABLE IF NOT EXISTS queue (
+            id TEXT PRIMARY KEY,
+            data TEXT NOT NULL,
+            diff_headers TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS resolved_outside_vote (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            diff_url TEXT NOT NULL,
+            data TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS history (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            pr TEXT NOT NULL,
+            data TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS quarantine (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            data TEXT NOT NULL
+        );",
+    )?;
+    Ok(!exists)
+}
+
+/// Upgrade every row of `table` written by an older version. Rows that can't be upgraded are moved to the
+/// quarantine table
+fn migrate_rows<T: Migrate + serde::Serialize>(
+    connection: &mut Connection,
+    table: &str,
+    key: &str,
+) -> rusqlite::Result<()> {
+    let transaction = connection.transaction()?;
+    let rows: Vec<(rusqlite::types::Value, String)> = {
+        let mut statement = transaction.prepare(&format!("SELECT {key}, data FROM {table}"))?;
+        let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect::<rusqlite::Result<_>>()?
+    };
+
+    for (id, data) in rows {
+        let stored = match serde_json::from_str(&data) {
+            Ok(value) => Versioned::from_stored(value),
+            Err(e) => Versioned {
+                version: 0,
+                data: serde_json::Value::String(format!("{data} ({e})")),
+            },
+        };
+        if stored.version == migrations::CURRENT_VERSION {
+            continue;
+        }
+        let mut quarantine = Vec::new();
+        match migrations::load::<T>(stored, &mut quarantine) {
+            Some(record) => {
+                transaction.execute(
+                    &format!("UPDATE {table} SET data = ?1 WHERE {key} = ?2"),
+                    params![
+                        serde_json::to_string(&Versioned::current(record)).unwrap(),
+                        id
+                    ],
+                )?;
+            }
+            None => {
+                for record in quarantine {
+                    put_quarantine(&transaction, &record)?;
+                }
+                transaction.execute(
+                    &format!("DELETE FROM {table} WHERE {key} = ?1"),
+                    params![id],
+                )?;
+            }
+        }
+    }
+    transaction.commit()
+}
+
+fn put_history(connection: &Connection, record: &HistoryRecord) -> rusqlite::Result<()> {
+    connection.execute(
+        "INSERT INTO history (pr, data) VALUES (?1, ?2)",
+        params![
+            record.id.key(),
+            serde_json::to_string(&Versioned::current(record)).unwrap()
+        ],
+    )?;
+    Ok(())
+}
+
+fn put_quarantine(connection: &Connection, record: &QuarantinedRecord) -> rusqlite::Result<()> {
+    connection.execute(
+        "INSERT INTO quarantine (data) VALUES (?1)",
+        params![serde_json::to_string(record).unwrap()],
+    )?;
+    Ok(())
+}
+
+fn get_pr(connection: &Connection, id: &PrId) -> rusqlite::Result<Option<PullRequestInfo>> {
+    let data: Option<String> = connection
+        .query_row(
+            "SELECT data FROM queue WHERE id = ?1",
+            params![id.key()],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(data.and_then(|data| parse(&data)))
+}
+
+fn put_pr(connection: &Connection, pr: &PullRequestInfo) -> rusqlite::Result<()> {
+    connection.execute(
+        "INSERT INTO queue (id, data, diff_headers) VALUES (?1, ?2, ?3)
+        ON CONFLICT(id) DO UPDATE SET data = excluded.data, diff_headers = excluded.diff_headers",
+        params![
+            pr.pull_request.id().key(),
+            serde_json::to_string(&Versioned::current(pr)).unwrap(),
+            repo_config::diff_headers(&pr.pull_request.diff)
+        ],
+    )?;
+    Ok(())
+}
+
+/// Rows are upgraded when the store is opened, so anything unreadable here is skipped rather than quarantined
+fn parse<T: Migrate>(data: &str) -> Option<T> {
+    let upgraded = serde_json::from_str(data)
+        .map_err(|e| e.to_string())
+        .and_then(|value| T::upgrade(Versioned::from_stored(value)));
+    match upgraded {
+        Ok(value) => Some(value),
+        Err(e) => {
+            println!("Skipping unreadable row: {}", e);
+            None
+        }
+    }
+}
+
+fn load_all<T: Migrate>(connection: &Connection, query: &str) -> rusqlite::Result<Vec<T>> {
+    let mut statement = connection.prepare(query)?;
+    let rows = statement.query_map([], |row| row.get::<_, String>(0))?;
+    let rows = rows.collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(rows.iter().filter_map(|data| parse(data)).collect())
+}
+
+fn insert_resolved(
+    connection: &Connection,
+    resolved: &ResolvedOutsideVote,
+) -> rusqlite::Result<()> {
+    connection.execute(
+        "INSERT INTO resolved_outside_vote (diff_url, data) VALUES (?1, ?2)",
+        params![
+            resolved.pull_request.diff_url,
+            serde_json::to_string(&Versioned::current(resolved)).unwrap()
+        ],
+    )?;
+    Ok(())
+}
+
+fn update_pr(
+    connection: &mut Connection,
+    id: &PrId,
+    f: &mut dyn FnMut(&mut PullRequestInfo),
+) -> rusqlite::Result<Option<PullRequestInfo>> {
+    let transaction = connection.transaction()?;
+    let Some(mut pr) = get_pr(&transaction, id)? else {
+        return Ok(None);
+    };
+    let before = pr.clone();
+    f(&mut pr);
+    if pr != before {
+        put_pr(&transaction, &pr)?;
+        transaction.commit()?;
+    }
+    Ok(Some(pr))
+}
+
+fn remove_pr(connection: &mut Connection, id: &PrId) -> rusqlite::Result<Option<PullRequestInfo>> {
+    let transaction = connection.transaction()?;
+    let Some(pr) = get_pr(&transaction, id)? else {
+        return Ok(None);
+    };
+    transaction.execute("DELETE FROM queue WHERE id = ?1", params![id.key()])?;
+    transaction.commit()?;
+    Ok(Some(pr))
+}
+
+/// Read everything in one transaction, so the export is a consistent snapshot
+fn export_state(connection: &mut Connection) -> rusqlite::Result<State> {
+    let transaction = connection.transaction()?;
+    let prs = load_all::<PullRequestInfo>(&transaction, "SELECT data FROM queue")?
+        .into_iter()
+        .map(|pr| (pr.pull_request.id().key(), pr))
+        .collect();
+    let resolved_outside_vote = load_all(
+        &transaction,
+        "SELECT data FROM resolved_outside_vote ORDER BY id",
+    )?;
+    let history = load_all(&transaction, "SELECT data FROM history ORDER BY id")?;
+    transaction.commit()?;
+    Ok(State {
+        prs,
+        resolved_outside_vote,
+        history,
+    })
+}
+
+/// Replace everything with `state`, all at once
+fn import_state(connection: &mut Connection, state: &State) -> rusqlite::Result<()> {
+    let transaction = connection.transaction()?;
+    transaction.execute_batch(
+        "DELETE FROM queue; DELETE FROM resolved_outside_vote; DELETE FROM history;",
+    )?;
+    for pr in state.prs.values() {
+        put_pr(&transaction, pr)?;
+    }
+    for resolved in &state.resolved_outside_vote {
+        insert_resolved(&transaction, resolved)?;
+    }
+    for record in &state.history {
+        put_history(&transaction, record)?;
+    }
+    transaction.commit()
+}
+
+impl Store for SqliteStore {
+    fn get(&self, id: &PrId) -> std::io::Result<Option<PullRequestInfo>> {
+        let connection = self.connection.lock().unwrap();
+        get_pr(&connection, id).map_err(std::io::Error::other)
+    }
+
+    fn all(&self) -> std::io::Result<Vec<PullRequestInfo>> {
+        let connection = self.connection.lock().unwrap();
+        load_all(&connection, "SELECT data FROM queue").map_err(std::io::Error::other)
+    }
+
+    fn all_without_diffs(&self) -> std::io::Result<Vec<PullRequestInfo>> {
+        let connection = self.connection.lock().unwrap();
+        // The diff is swapped for its headers before the row leaves SQLite, so it is never parsed
+        load_all(
+            &connection,
+            "SELECT json_set(data, '$.data.pull_request.diff', diff_headers) FROM queue",
+        )
+        .map_err(std::io::Error::other)
+    }
+
+    fn insert(&self, pr: PullRequestInfo) -> std::io::Result<()> {
+        let connection = self.connection.lock().unwrap();
+        put_pr(&connection, &pr).map_err(std::io::Error::other)
+    }
+
+    fn update(
+        &self,
+        id: &PrId,
+        f: &mut dyn FnMut(&mut PullRequestInfo),
+    ) -> std::io::Result<Option<PullRequestInfo>> {
+        let mut connection = self.connection.lock().unwrap();
+        update_pr(&mut connection, id, f).map_err(std::io::Error::other)
+    }
+
+    fn remove(&self, id: &PrId) -> std::io::Result<Option<PullRequestInfo>> {
+        let mut connection = self.connection.lock().unwrap();
+        remove_pr(&mut connection, id).map_err(std::io::Error::other)
+    }
+
+    fn record_resolved(&self, resolved: ResolvedOutsideVote) -> std::io::Result<()> {
+        let connection = self.connection.lock().unwrap();
+        insert_resolved(&connection, &resolved).map_err(std::io::Error::other)
+    }
+
+    fn resolved(&self) -> std::io::Result<Vec<ResolvedOutsideVote>> {
+        let connection = self.connection.lock().unwrap();
+        load_all(
+            &connection,
+            "SELECT data FROM resolved_outside_vote ORDER BY id",
+        )
+        .map_err(std::io::Error::other)
+    }
+
+    fn record_history(&self, record: HistoryRecord) -> std::io::Result<()> {
+        let connection = self.connection.lock().unwrap();
+        put_history(&connection, &record).map_err(std::io::Error::other)
+    }
+
+    fn history(&self) -> std::io::Result<Vec<HistoryRecord>> {
+        let connection = self.connection.lock().unwrap();
+        load_all(&connection, "SELECT data FROM history ORDER BY id").map_err(std::io::Error::other)
+    }
+
+    fn export(&self) -> std::io::Result<State> {
+        let mut connection = self.connection.lock().unwrap();
+        export_state(&mut connection).map_err(std::io::Error::other)
+    }
+
+    fn import(&self, state: State) -> std::io::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        import_state(&mut connection, &state).map_err(std::io::Error::other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Direction;
+    use std::path::PathBuf;
+
+    /// An empty directory for a database, removed again once the test is done
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("gitlucky-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            Self(dir)
+        }
+
+        fn database(&self) -> PathBuf {
+            self.0.join("gitlucky.sqlite")
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn queued_pr() -> PullRequestInfo {
+        serde_json::from_str(include_str!("../fixtures/queued_pr.json")).unwrap()
+    }
+
+    fn finalized_pr() -> HistoryRecord {
+        serde_json::from_str(include_str!("../fixtures/finalized_pr.json")).unwrap()
+    }
+
+    fn resolved(pr: &PullRequestInfo) -> ResolvedOutsideVote {
+        ResolvedOutsideVote {
+            pull_request: pr.pull_request.clone(),
+            left_votes: pr.left_votes(),
+            right_votes: pr.right_votes(),
+            merged: false,
+            resolved_time: pr.creation_time,
+        }
+    }
+
+    #[test]
+    fn changes_survive_a_restart() {
+        let dir = TestDir::new("sqlite-restart");
+        let pr = queued_pr();
+        let id = pr.pull_request.id();
+        {
+            let store = SqliteStore::open(&dir.database()).unwrap();
+            store.insert(pr.clone()).unwrap();
+            store
+                .update(&id, &mut |pr| {
+                    pr.votes.insert("github:6".to_string(), Direction::Right);
+                })
+                .unwrap();
+            store.record_resolved(resolved(&pr)).unwrap();
+            store.record_history(finalized_pr()).unwrap();
+        }
+        let store = SqliteStore::open(&dir.database()).unwrap();
+        let queued = store.get(&id).unwrap().unwrap();
+        assert_eq!(queued.right_votes(), pr.right_votes() + 1);
+        assert_eq!(store.resolved().unwrap(), vec![resolved(&pr)]);
+        assert_eq!(store.history().unwrap(), vec![finalized_pr()]);
+
+        assert_eq!(store.remove(&id).unwrap(), Some(queued));
+        assert_eq!(store.remove(&id).unwrap(), None);
+        assert!(store.all().unwrap().is_empty());
+    }
+
+    #[test]
+    fn the_queue_can_be_listed_without_the_diffs() {
+        let dir = TestDir::new("sqlite-without-diffs");
+        let pr = queued_pr();
+        let store = SqliteStore::open(&dir.database()).unwrap();
+        store.insert(pr.clone()).unwrap();
+
+        assert_eq!(store.all_without_diffs().unwrap(), vec![pr.without_diff()]);
+        assert_eq!(
+            pr.without_diff().pull_request.diff,
+            "diff --git a/README.md b/README.md\n"
+        );
+        assert_eq!(store.get(&pr.pull_request.id()).unwrap(), Some(pr));
+    }
+
+    #[test]
+    fn exports_can_be_imported_again() {
+        let dir = TestDir::new("sqlite-export");
+        let pr = queued_pr();
+        let store = SqliteStore::open(&dir.database()).unwrap();
+        store.insert(pr.clone()).unwrap();
+        store.record_resolved(resolved(&pr)).unwrap();
+        store.record_history(finalized_pr()).unwrap();
+
+        let exported = store.export().unwrap();
+        assert_eq!(exported.prs[&pr.pull_request.id().key()], pr);
+        assert_eq!(exported.resolved_outside_vote, vec![resolved(&pr)]);
+        assert_eq!(exported.history, vec![finalized_pr()]);
+
+        // Importing replaces what was there
+        store.import(State::default()).unwrap();
+        assert!(store.all().unwrap().is_empty());
+        store.import(exported).unwrap();
+        assert_eq!(store.all().unwrap(), vec![pr]);
+        assert_eq!(store.history().unwrap(), vec![finalized_pr()]);
+    }
+
+    #[test]
+    fn the_files_of_older_versions_are_imported() {
+        let dir = TestDir::new("sqlite-legacy");
+        std::fs::create_dir_all(&dir.0).unwrap();
+        let pr = queued_pr();
+        std::fs::write(
+            dir.0.join("prs.json"),
+            serde_json::to_vec(&vec![pr.clone()]).unwrap(),
+        )
+        .unwrap();
+        std::fs::write(
+            dir.0.join("resolved.json"),
+            serde_json::to_vec(&vec![resolved(&pr)]).unwrap(),
+        )
+        .unwrap();
+
+        let store = SqliteStore::open(&dir.database()).unwrap();
+        assert_eq!(store.all().unwrap(), vec![pr.clone()]);
+        assert_eq!(store.resolved().unwrap(), vec![resolved(&pr)]);
+
+        // Only a new database imports them, so PRs that left the queue since don't come back
+        store.remove(&pr.pull_request.id()).unwrap();
+        drop(store);
+        let store = SqliteStore::open(&dir.database()).unwrap();
+        assert!(store.all().unwrap().is_empty());
+    }
+
+    #[test]
+    fn rows_of_older_versions_are_upgraded() {
+        let dir = TestDir::new("sqlite-migrate");
+        std::fs::create_dir_all(&dir.0).unwrap();
+        // Unversioned PRs were voted on for a day
+        let pr = PullRequestInfo {
+            deadline: queued_pr().creation_time + chrono::Duration::days(1),
+            ..queued_pr()
+        };
+        let mut unversioned = serde_json::to_value(&pr).unwrap();
+        unversioned.as_object_mut().unwrap().remove("deadline");
+        {
+            let connection = Connection::open(dir.database()).unwrap();
+            create_tables(&connection).unwrap();
+            connection
+                .execute(
+                    "INSERT INTO queue (id, data, diff_headers) VALUES (?1, ?2, ?3), ('broken', '{', '')",
+                    params![
+                        pr.pull_request.id().key(),
+                        unversioned.to_string(),
+                        repo_config::diff_headers(&pr.pull_request.diff)
+                    ],
+                )
+                .unwrap();
+        }
+
+        let store = SqliteStore::open(&dir.database()).unwrap();
+        assert_eq!(store.all().unwrap(), vec![pr]);
+        let connection = store.connection.lock().unwrap();
+        let quarantined: usize = connection
+            .query_row("SELECT COUNT(*) FROM quarantine", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(quarantined, 1);
+    }
+}
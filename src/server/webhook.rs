@@ -0,0 +1,228 @@
+use hmac::{Hmac, Mac};
+use octocrab::models::events::payload::{IssueCommentEventPayload, PullRequestEventPayload};
+use serde::Deserialize;
+use sha2::Sha256;
+
+/// The header GitHub puts the HMAC-SHA256 signature of the webhook body in
+pub const SIGNATURE_HEADER: &str = "X-Hub-Signature-256";
+
+/// Read the secret configured for the GitHub App webhook
+pub fn webhook_secret() -> Vec<u8> {
+    std::env::var("GITHUB_WEBHOOK_SECRET")
+        .expect("Missing environment variable: GITHUB_WEBHOOK_SECRET")
+        .into_bytes()
+}
+
+/// Check the `sha256=<hex>` signature GitHub sent against the raw body of the request
+pub fn verify_signature(secret: &[u8], signature: Option<&str>, body: &[u8]) -> bool {
+    let Some(signature) = signature.and_then(|s| s.strip_prefix("sha256=")) else {
+        return false;
+    };
+    let Some(signature) = decode_hex(signature) else {
+        return false;
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(body);
+    // verify_slice compares in constant time
+    mac.verify_slice(&signature).is_ok()
+}
+
+pub fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    // from_str_radix would also take a sign, like the `+` in `+f`
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// The header GitHub puts the name of the event that triggered the webhook in
+pub const EVENT_HEADER: &str = "X-GitHub-Event";
+
+/// A webhook delivery from GitHub, deserialized based on the `X-GitHub-Event` header
+#[derive(Debug)]
+pub enum WebhookEvent {
+    Ping(PingPayload),
+    PullRequest(Box<PullRequestEventPayload>),
+    Installation(InstallationPayload),
+    IssueComment(Box<IssueCommentEventPayload>),
+    CheckSuite(CheckSuitePayload),
+    /// An action of an event we handle, but not that action
+    IgnoredAction {
+        event: String,
+        action: String,
+    },
+    /// An event we don't handle. We still acknowledge it so GitHub doesn't mark the delivery as failed
+    Unknown(String),
+}
+
+impl WebhookEvent {
+    pub fn parse(event: &str, body: &[u8]) -> Result<Self, serde_json::Error> {
+        Ok(match event {
+            "ping" => Self::Ping(serde_json::from_slice(body)?),
+            "pull_request" => {
+                // octocrab can't deserialize actions it doesn't know, like `milestoned`, so look at the action first
+                let ActionPayload { action } = serde_json::from_slice(body)?;
+                if !PULL_REQUEST_ACTIONS.contains(&action.as_str()) {
+                    return Ok(Self::IgnoredAction {
+                        event: event.to_string(),
+                        action,
+                    });
+                }
+                Self::PullRequest(serde_json::from_slice(body)?)
+            }
+            "installation" | "installation_repositories" => {
+                Self::Installation(serde_json::from_slice(body)?)
+            }
+            "issue_comment" => Self::IssueComment(serde_json::from_slice(body)?),
+            "check_suite" => Self::CheckSuite(serde_json::from_slice(body)?),
+            other => Self::Unknown(other.to_string()),
+        })
+    }
+}
+
+/// The `pull_request` actions that change the queue
+const PULL_REQUEST_ACTIONS: &[&str] = &["opened", "reopened", "synchronize", "closed"];
+
+/// Just the `action` every payload of an event with several actions has
+#[derive(Debug, Deserialize)]
+struct ActionPayload {
+    action: String,
+}
+
+/// Sent once when the webhook is first configured
+#[derive(Debug, Deserialize)]
+pub struct PingPayload {
+    pub zen: Option<String>,
+    pub hook_id: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InstallationPayload {
+    pub action: String,
+    pub installation: Installation,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Installation {
+    pub id: u64,
+    pub account: Option<Account>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Account {
+    pub login: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CheckSuitePayload {
+    pub action: String,
+    pub check_suite: CheckSuite,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CheckSuite {
+    pub head_sha: String,
+    pub conclusion: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ping delivery as GitHub sends it, signed with [`SECRET`]
+    const PING: &[u8] = include_bytes!("fixtures/ping.json");
+    const SECRET: &[u8] = b"gitlucky-test-secret";
+    const PING_SIGNATURE: &str =
+        "sha256=f6191d8322cfaf5a123b35c0df620bd7b1eb51129cf0a73f167681fb9df58e50";
+
+    #[test]
+    fn accepts_the_example_from_the_github_docs() {
+        let signature = "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+        assert!(verify_signature(
+            b"It's a Secret to Everybody",
+            Some(signature),
+            b"Hello, World!"
+        ));
+    }
+
+    #[test]
+    fn accepts_a_signed_delivery() {
+        assert!(verify_signature(SECRET, Some(PING_SIGNATURE), PING));
+        assert!(matches!(
+            WebhookEvent::parse("ping", PING),
+            Ok(WebhookEvent::Ping(PingPayload {
+                hook_id: Some(537203214),
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn rejects_a_tampered_body() {
+        let tampered = String::from_utf8(PING.to_vec())
+            .unwrap()
+            .replace("Design for failure.", "Merge everything.");
+        assert!(!verify_signature(
+            SECRET,
+            Some(PING_SIGNATURE),
+            tampered.as_bytes()
+        ));
+    }
+
+    #[test]
+    fn rejects_the_wrong_secret() {
+        assert!(!verify_signature(
+            b"some-other-secret",
+            Some(PING_SIGNATURE),
+            PING
+        ));
+    }
+
+    #[test]
+    fn rejects_a_signature_without_the_prefix() {
+        let unprefixed = PING_SIGNATURE.strip_prefix("sha256=").unwrap();
+        assert!(!verify_signature(SECRET, Some(unprefixed), PING));
+        let sha1 = PING_SIGNATURE.replace("sha256=", "sha1=");
+        assert!(!verify_signature(SECRET, Some(&sha1), PING));
+        assert!(!verify_signature(SECRET, None, PING));
+    }
+
+    #[test]
+    fn rejects_malformed_hex() {
+        let odd_length = &PING_SIGNATURE[..PING_SIGNATURE.len() - 1];
+        assert!(!verify_signature(SECRET, Some(odd_length), PING));
+        let not_hex = PING_SIGNATURE.replace('f', "g");
+        assert!(!verify_signature(SECRET, Some(&not_hex), PING));
+    }
+
+    #[test]
+    fn ignores_pull_request_actions_octocrab_does_not_know() {
+        let body = br#"{"action":"milestoned","number":7,"milestone":{"title":"v1"}}"#;
+        assert!(matches!(
+            WebhookEvent::parse("pull_request", body),
+            Ok(WebhookEvent::IgnoredAction { action, .. }) if action == "milestoned"
+        ));
+    }
+
+    #[test]
+    fn rejects_a_pull_request_payload_without_an_action() {
+        assert!(WebhookEvent::parse("pull_request", br#"{"number":7}"#).is_err());
+        // A handled action still needs the full payload
+        assert!(WebhookEvent::parse("pull_request", br#"{"action":"opened"}"#).is_err());
+    }
+
+    #[test]
+    fn decodes_hex() {
+        assert_eq!(decode_hex("00ff7a"), Some(vec![0x00, 0xff, 0x7a]));
+        assert_eq!(decode_hex("00FF7A"), Some(vec![0x00, 0xff, 0x7a]));
+        assert_eq!(decode_hex(""), Some(Vec::new()));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(decode_hex("+1"), None);
+        // Multi-byte characters can't be split into pairs of hex digits
+        assert_eq!(decode_hex("é0"), None);
+    }
+}
@@ -0,0 +1,196 @@
+use dioxus::prelude::*;
+
+use crate::ApiError;
+use crate::HistoryPage;
+use crate::HistoryRecord;
+use crate::Outcome;
+use crate::Route;
+
+const OUTCOMES: [Outcome; 4] = [
+    Outcome::Merged,
+    Outcome::MergeFailed,
+    Outcome::Closed,
+    Outcome::LeftOpen,
+];
+
+/// The name of an outcome in the `/history` API
+fn outcome_name(outcome: Outcome) -> &'static str {
+    match outcome {
+        Outcome::Merged => "merged",
+        Outcome::MergeFailed => "merge_failed",
+        Outcome::Closed => "closed",
+        Outcome::LeftOpen => "left_open",
+    }
+}
+
+fn outcome_label(outcome: Outcome) -> &'static str {
+    match outcome {
+        Outcome::Merged => "✅ merged",
+        Outcome::MergeFailed => "⚠️ merge failed",
+        Outcome::Closed => "❌ closed",
+        Outcome::LeftOpen => "🤷 left open",
+    }
+}
+
+/// Fetch a page of the finalized PRs that match the filters, newest first
+async fn get_history(
+    owner: String,
+    repo: String,
+    outcome: Option<Outcome>,
+    cursor: Option<String>,
+) -> Result<HistoryPage, String> {
+    let mut query = Vec::new();
+    if !owner.trim().is_empty() {
+        query.push(("repo_owner", owner.trim().to_string()));
+    }
+    if !repo.trim().is_empty() {
+        query.push(("repo_name", repo.trim().to_string()));
+    }
+    if let Some(outcome) = outcome {
+        query.push(("outcome", outcome_name(outcome).to_string()));
+    }
+    if let Some(cursor) = cursor {
+        query.push(("cursor", cursor));
+    }
+    let response = gloo_net::http::Request::get("/history")
+        .query(query)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !response.ok() {
+        let error = response
+            .json::<ApiError>()
+            .await
+            .map_err(|e| e.to_string())?;
+        return Err(error.message);
+    }
+    response.json().await.map_err(|e| e.to_string())
+}
+
+/// The archive of every finalized vote
+#[component]
+pub fn History() -> Element {
+    let mut owner = use_signal(String::new);
+    let mut repo = use_signal(String::new);
+    let mut outcome = use_signal(|| None::<Outcome>);
+    // Where the page starts, or the newest PRs if it isn't set
+    let mut cursor = use_signal(|| None::<String>);
+    let page =
+        use_resource(
+            move || async move { get_history(owner(), repo(), outcome(), cursor()).await },
+        );
+
+    rsx! {
+        div { class: "flex flex-col w-[100vw] min-h-[100vh] p-8 gap-4 font-mono",
+            div { class: "flex flex-row justify-between items-center",
+                h1 { class: "text-2xl font-bold", "📜 History" }
+                Link { to: Route::Home {}, "⬅️ back to voting" }
+            }
+            div { class: "flex flex-row flex-wrap gap-4",
+                input {
+                    class: "border px-1",
+                    placeholder: "owner",
+                    value: owner(),
+                    oninput: move |evt| {
+                        cursor.set(None);
+                        owner.set(evt.value());
+                    },
+                }
+                input {
+                    class: "border px-1",
+                    placeholder: "repo",
+                    value: repo(),
+                    oninput: move |evt| {
+                        cursor.set(None);
+                        repo.set(evt.value());
+                    },
+                }
+                select {
+                    class: "border px-1",
+                    onchange: move |evt| {
+                        cursor.set(None);
+                        outcome.set(OUTCOMES.into_iter().find(|&o| outcome_name(o) == evt.value()));
+                    },
+                    option { value: "", "any outcome" }
+                    for o in OUTCOMES {
+                        option {
+                            value: outcome_name(o),
+                            selected: outcome() == Some(o),
+                            {outcome_label(o)}
+                        }
+                    }
+                }
+            }
+            match &*page.read() {
+                None => rsx! { "loading..." },
+                Some(Err(error)) => rsx! { "Couldn't load the history: {error}" },
+                Some(Ok(page)) if page.items.is_empty() => rsx! { "No finalized PRs match." },
+                Some(Ok(page)) => {
+                    let next_cursor = page.next_cursor.clone();
+                    rsx! {
+                        table { class: "w-full text-left",
+                            thead {
+                                tr {
+                                    th { "PR" }
+                                    th { "Outcome" }
+                                    th { "Votes" }
+                                    th { "Policy" }
+                                    th { "Finalized" }
+                                }
+                            }
+                            tbody {
+                                for record in page.items.iter() {
+                                    HistoryRow { key: "{record.id.key()}{record.finalized_time}", record: record.clone() }
+                                }
+                            }
+                        }
+                        div { class: "flex flex-row gap-4",
+                            if cursor().is_some() {
+                                button { onclick: move |_| cursor.set(None), "newest" }
+                            }
+                            if next_cursor.is_some() {
+                                button { onclick: move |_| cursor.set(next_cursor.clone()), "older ➡️" }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[component]
+fn HistoryRow(record: HistoryRecord) -> Element {
+    let id = &record.id;
+    let url = format!(
+        "https://github.com/{}/{}/pull/{}",
+        id.owner, id.repo, id.number
+    );
+    let tally = record.tally;
+    let finalized = record.finalized_time.format("%Y-%m-%d %H:%M UTC");
+
+    rsx! {
+        tr { class: "border-t align-top",
+            td {
+                a { href: "{url}", "{id.key()}" }
+                div { "{record.title} by {record.author}" }
+                details {
+                    summary { "comment" }
+                    "{record.comment}"
+                }
+            }
+            td {
+                {outcome_label(record.outcome)}
+                if let Some(sha) = &record.merge_sha {
+                    div { "{sha.get(..7).unwrap_or(sha)}" }
+                }
+                if let Some(error) = &record.error {
+                    div { "{error}" }
+                }
+            }
+            td { "✅ {tally.accepted} ❌ {tally.denied} ⏭️ {tally.abstained}" }
+            td { "{record.policy}" }
+            td { "{finalized}" }
+        }
+    }
+}
@@ -1,13 +1,27 @@
 use crate::dioxus_elements::geometry::ClientSpace;
 use dioxus::prelude::*;
 use euclid::Point2D;
+use futures_util::StreamExt;
+use gloo_net::eventsource::futures::EventSource;
 use std::{fmt::Display, str::FromStr};
 use web_sys::RequestMode;
 
+use crate::ApiError;
+use crate::ApiErrorCode;
 use crate::Direction;
+use crate::PrFilter;
+use crate::PrId;
 use crate::PullRequest;
+use crate::QueueEvent;
+use crate::QueueTally;
+use crate::Route;
+use crate::User;
 
-async fn get_pr() -> PullRequest {
+/// The local storage key the filter is saved under
+const FILTER_KEY: &str = "gitlucky_filter";
+
+/// Fetch a PR to vote on. Returns none once no queued PR matches `filter`
+async fn get_pr(filter: &PrFilter) -> Option<PullRequest> {
     // return PullRequest {
     //     diff_url: "https://github.com/DioxusLabs/docsite/pull/467.diff".to_string(),
     //     title: String::new(),
@@ -25,13 +39,26 @@ async fn get_pr() -> PullRequest {
     // };
     loop {
         let result = gloo_net::http::Request::get("/pr")
+            .query(filter_query(filter))
             .send()
             .await;
         match result {
+            // Rate limited, wait and try again
+            Ok(result) if result.status() == 429 => {
+                tracing::warn!("Fetching PRs too quickly, retrying");
+            }
+            Ok(result) if result.status() == 204 => {
+                tracing::info!("No PRs to vote on");
+                return None;
+            }
+            Ok(result) if !result.ok() => {
+                let error = result.json::<ApiError>().await;
+                tracing::error!("Error fetching PR: {:?}", error);
+            }
             Ok(result) => {
                 let result = result.json::<PullRequest>().await.unwrap();
                 tracing::info!("Fetched PR: {:?}", result);
-                return result;
+                return Some(result);
             }
             Err(err) => {
                 tracing::error!("Error fetching PR: {:?}", err);
@@ -44,32 +71,204 @@ async fn get_pr() -> PullRequest {
     }
 }
 
+/// Fetch a PR to vote on and get it ready to show
+async fn get_card(filter: &PrFilter) -> Option<PRData> {
+    get_pr(filter).await.map(PRData::from)
+}
+
+/// Follow the changes to the queue from `/events` and hand each one to `on_event`. The browser reconnects if
+/// the connection drops
+async fn watch_queue(mut on_event: impl FnMut(QueueEvent)) {
+    let mut source = match EventSource::new("/events") {
+        Ok(source) => source,
+        Err(err) => {
+            tracing::error!("Error following the queue: {:?}", err);
+            return;
+        }
+    };
+    let Ok(mut messages) = source.subscribe("message") else {
+        return;
+    };
+    while let Some(message) = messages.next().await {
+        let data = match message {
+            Ok((_, message)) => message.data().as_string().unwrap_or_default(),
+            Err(err) => {
+                tracing::warn!("Lost the connection to the queue: {:?}", err);
+                continue;
+            }
+        };
+        match serde_json::from_str(&data) {
+            Ok(event) => on_event(event),
+            Err(err) => tracing::error!("Unreadable queue event {:?}: {:?}", data, err),
+        }
+    }
+}
+
+/// Change the cards showing the PR `id`, whatever revision they show
+fn update_cards(
+    mut data_source: Signal<[Option<PRData>; 2]>,
+    id: &PrId,
+    mut change: impl FnMut(&mut PRData),
+) {
+    for card in data_source.write().iter_mut().flatten() {
+        if card.id.key() == id.key() {
+            change(card);
+        }
+    }
+}
+
+/// `filter` as query parameters for `/pr`. Fields that aren't set are left out
+fn filter_query(filter: &PrFilter) -> Vec<(&'static str, String)> {
+    let text = [
+        ("repo_owner", &filter.repo_owner),
+        ("repo_name", &filter.repo_name),
+        ("extensions", &filter.extensions),
+    ];
+    let numbers = [
+        ("min_additions", filter.min_additions),
+        ("max_additions", filter.max_additions),
+        ("min_deletions", filter.min_deletions),
+        ("max_deletions", filter.max_deletions),
+    ];
+    text.into_iter()
+        .filter_map(|(name, value)| Some((name, value.clone()?)))
+        .chain(
+            numbers
+                .into_iter()
+                .filter_map(|(name, value)| Some((name, value?.to_string()))),
+        )
+        .collect()
+}
+
+/// The filter saved in local storage, or no filter if none was saved
+async fn load_filter() -> PrFilter {
+    let saved = document::eval(&format!("return localStorage.getItem('{FILTER_KEY}')"))
+        .join::<Option<String>>()
+        .await;
+    saved
+        .ok()
+        .flatten()
+        .and_then(|saved| serde_json::from_str(&saved).ok())
+        .unwrap_or_default()
+}
+
+fn save_filter(filter: &PrFilter) {
+    // Encoding the JSON again turns it into a JavaScript string literal
+    let saved = serde_json::to_string(&serde_json::to_string(filter).unwrap()).unwrap();
+    document::eval(&format!("localStorage.setItem('{FILTER_KEY}', {saved})"));
+}
+
+/// The voter signed in with GitHub, if any
+async fn get_user() -> Option<User> {
+    let result = gloo_net::http::Request::get("/auth/me").send().await.ok()?;
+    result.json::<Option<User>>().await.ok().flatten()
+}
+
 #[derive(Clone, Copy, PartialEq)]
 enum TransitioningDirection {
     Left,
     Right,
+    Up,
 }
 
 #[component]
 pub fn Home() -> Element {
     let mut transitioning = use_signal(|| None);
     let mut data_source = use_signal(|| [None, None]);
-    use_future(move || async move {
+    let mut empty = use_signal(|| false);
+    // The card the voter swiped last, so the swipe can be undone
+    let mut last_swiped = use_signal(|| None::<PRData>);
+    // Why the last vote didn't count, until the next one
+    let mut notice = use_signal(|| None::<String>);
+    // None until the saved filter is loaded
+    let mut filter = use_signal(|| None::<PrFilter>);
+    let user = use_resource(get_user);
+    let mut cards = use_future(move || async move {
+        let current_filter = match filter.peek().clone() {
+            Some(current_filter) => current_filter,
+            None => {
+                let saved = load_filter().await;
+                filter.set(Some(saved.clone()));
+                saved
+            }
+        };
         for dst_i in 0..2 {
-            let info: PullRequest = get_pr().await;
-            let text = &info.diff;
-            let diff = GitDiff::from_str(&text).unwrap();
-            data_source.write()[dst_i] = Some(PRData {
-                source_url: info.diff_url,
-                repo: info.repo_name,
-                pull_request_title: info.branch_to_merge,
-                user: info.author,
-                user_avatar: info.profile_pic_url,
-                diff,
-            });
+            let Some(card) = get_card(&current_filter).await else {
+                empty.set(true);
+                return;
+            };
+            data_source.write()[dst_i] = Some(card);
         }
     });
     let mut count = use_signal(|| 0);
+    let apply_filter = move |new_filter: PrFilter| {
+        save_filter(&new_filter);
+        filter.set(Some(new_filter));
+        data_source.set([None, None]);
+        last_swiped.set(None);
+        empty.set(false);
+        cards.restart();
+    };
+    // New PRs show up on the empty screen, cards follow the votes and commits on their PR, and cards for PRs
+    // whose vote is over are replaced
+    use_future(move || async move {
+        watch_queue(move |event| match event {
+            QueueEvent::Queued { .. } if *empty.peek() => {
+                data_source.set([None, None]);
+                empty.set(false);
+                cards.restart();
+            }
+            QueueEvent::Voted { id, tally } => {
+                update_cards(data_source, &id, |card| card.tally = Some(tally));
+            }
+            QueueEvent::Updated { pull_request, tally } => {
+                update_cards(data_source, &pull_request.id(), |card| {
+                    *card = PRData {
+                        tally: Some(tally),
+                        extended_until: card.extended_until,
+                        ..PRData::from(pull_request.clone())
+                    }
+                });
+            }
+            QueueEvent::Extended { id, deadline } => {
+                update_cards(data_source, &id, |card| card.extended_until = Some(deadline));
+            }
+            QueueEvent::Finalized { id } | QueueEvent::Removed { id } => {
+                let stale = data_source
+                    .peek()
+                    .iter()
+                    .position(|card| card.as_ref().is_some_and(|card| card.id.key() == id.key()));
+                let Some(i) = stale else {
+                    return;
+                };
+                spawn(async move {
+                    let current_filter = filter.peek().clone().unwrap_or_default();
+                    match get_card(&current_filter).await {
+                        Some(card) => data_source.write()[i] = Some(card),
+                        None => empty.set(true),
+                    }
+                });
+            }
+            _ => {}
+        })
+        .await
+    });
+    if empty() {
+        let filtered = filter().is_some_and(|filter| filter != PrFilter::default());
+        return rsx! {
+            div { class: "flex flex-col items-center justify-center w-[100vw] h-[100vh] font-mono",
+                if filtered {
+                    "No PRs match your filter right now."
+                } else {
+                    "🎉 No PRs to vote on right now. Check back later!"
+                }
+                FilterPanel {
+                    filter: filter().unwrap_or_default(),
+                    on_apply: apply_filter,
+                }
+            }
+        };
+    }
     let [Some(first), Some(second)] = &*data_source.read_unchecked() else {
         return rsx! {"loading..."};
     };
@@ -83,33 +282,45 @@ pub fn Home() -> Element {
         let direction = match transition_direction {
             TransitioningDirection::Left => Direction::Left,
             TransitioningDirection::Right => Direction::Right,
+            TransitioningDirection::Up => Direction::Skip,
         };
         transitioning.set(Some(transition_direction));
+        notice.set(None);
+        // The card on screen, read before the next PR replaces it
+        let swiped = data_source.read_unchecked()[count() % 2].clone().unwrap();
+        let id = swiped.id.clone();
         let i = (count() + 1) % 2;
         spawn(async move {
-            let info: PullRequest = get_pr().await;
-            let text = &info.diff;
-            let diff = GitDiff::from_str(&text).unwrap();
-            data_source.write()[i] = Some(PRData {
-                source_url: info.diff_url,
-                repo: info.repo_name,
-                pull_request_title: info.branch_to_merge,
-                user: info.author,
-                user_avatar: info.profile_pic_url,
-                diff,
-            });
+            let current_filter = filter.peek().clone().unwrap_or_default();
+            let Some(card) = get_card(&current_filter).await else {
+                empty.set(true);
+                return;
+            };
+            data_source.write()[i] = Some(card);
             count += 1;
         });
-        let diff_url = {
-            let read = data_source.read_unchecked();
-            &read[0].as_ref().unwrap().source_url.to_string()
-        };
-        gloo_net::http::Request::post("/vote")
-            .json(&(diff_url, direction))
+        let response = gloo_net::http::Request::post("/vote")
+            .json(&(id, direction))
             .expect("Failed to serialize JSON")
             .send()
             .await
             .unwrap();
+        if response.status() == 401 {
+            // Votes need the device token from `/pr`. Signing in gets around a missing one
+            document::eval("window.location.href = '/auth/login'");
+        } else if !response.ok() {
+            let error = response.json::<ApiError>().await;
+            tracing::warn!("Vote was not counted: {:?}", error);
+            let title = &swiped.pull_request_title;
+            notice.set(Some(match error {
+                Ok(error) if error.code == ApiErrorCode::StaleRevision => format!(
+                    "New commits were pushed to {title} since it was shown, so your vote didn't count. Vote on it again when it comes back."
+                ),
+                Ok(error) => format!("Your vote on {title} didn't count: {}", error.message),
+                Err(_) => format!("Your vote on {title} didn't count"),
+            }));
+        }
+        last_swiped.set(response.ok().then_some(swiped));
 
         #[cfg(target_arch = "wasm32")]
         gloo_timers::future::sleep(std::time::Duration::from_secs(1)).await;
@@ -118,6 +329,32 @@ pub fn Home() -> Element {
         transitioning.set(None);
     };
 
+    // Retract the last vote and put its card back on top so it can be swiped again
+    let undo = move || async move {
+        let Some(swiped) = last_swiped() else {
+            return;
+        };
+        if transitioning().is_some() {
+            return;
+        }
+        let response = gloo_net::http::Request::post("/vote/retract")
+            .json(&swiped.id)
+            .expect("Failed to serialize JSON")
+            .send()
+            .await
+            .unwrap();
+        if !response.ok() {
+            let error = response.json::<ApiError>().await;
+            tracing::warn!("Vote was not retracted: {:?}", error);
+        }
+        last_swiped.set(None);
+        if response.ok() {
+            // The card underneath is replaced, the one on screen moves underneath
+            data_source.write()[(count() + 1) % 2] = Some(swiped);
+            count += 1;
+        }
+    };
+
     let handle_click_at_pos = move |pos: Point2D<f64, ClientSpace>| async move {
         tracing::info!("Clicked at: {:?}", pos);
         if transitioning().is_some() {
@@ -155,6 +392,47 @@ pub fn Home() -> Element {
                         "⬅️ reject"
                     }
                 }
+                div {
+                    class: "absolute left-1/2 -translate-x-1/2 p-8",
+                    onclick: move |evt| evt.stop_propagation(),
+                    ontouchstart: move |evt| evt.stop_propagation(),
+                    match &*user.read() {
+                        Some(Some(user)) => rsx! { "@{user.login}" },
+                        _ => rsx! {
+                            a { href: "/auth/login", "Sign in with GitHub" }
+                        },
+                    }
+                    button {
+                        class: "ml-4",
+                        onclick: move |_| async move {
+                            if transitioning().is_none() {
+                                submit(TransitioningDirection::Up).await;
+                            }
+                        },
+                        "⏭️ skip"
+                    }
+                    FilterPanel {
+                        filter: filter().unwrap_or_default(),
+                        on_apply: apply_filter,
+                    }
+                    Link { class: "ml-4", to: Route::History {}, "📜 history" }
+                    Link { class: "ml-4", to: Route::Leaderboard {}, "🏆 leaderboard" }
+                    if last_swiped().is_some() {
+                        button {
+                            class: "ml-4",
+                            onclick: move |_| undo(),
+                            "↩️ undo"
+                        }
+                    }
+                }
+                if let Some(notice) = notice() {
+                    div {
+                        class: "absolute top-16 left-1/2 -translate-x-1/2 p-2 border rounded bg-yellow-100 font-mono",
+                        onclick: move |evt| evt.stop_propagation(),
+                        ontouchstart: move |evt| evt.stop_propagation(),
+                        "⚠️ {notice}"
+                    }
+                }
                 div {
                     class: "text-right w-[50vw] p-8",
                     button {
@@ -176,6 +454,7 @@ pub fn Home() -> Element {
                             match dir {
                                 TransitioningDirection::Right => "right-card",
                                 TransitioningDirection::Left => "left-card",
+                                TransitioningDirection::Up => "up-card",
                             }
                         } else {
                             "down-card"
@@ -188,6 +467,123 @@ pub fn Home() -> Element {
     }
 }
 
+/// Lets the voter narrow down the PRs they are shown. Nothing changes until they apply the filter
+#[component]
+fn FilterPanel(filter: PrFilter, on_apply: EventHandler<PrFilter>) -> Element {
+    let mut open = use_signal(|| false);
+    let mut draft = use_signal(PrFilter::default);
+    let text = |value: &Option<String>| value.clone().unwrap_or_default();
+    let number = |value: Option<usize>| value.map(|value| value.to_string()).unwrap_or_default();
+
+    rsx! {
+        div { class: "inline-block relative",
+            button {
+                class: "ml-4",
+                onclick: move |_| {
+                    draft.set(filter.clone());
+                    open.toggle();
+                },
+                "🔍 filter"
+            }
+            if open() {
+                div { class: "absolute top-full left-1/2 -translate-x-1/2 z-10 flex flex-col gap-2 p-4 border rounded-xl bg-white font-mono text-sm",
+                    label { class: "flex flex-row justify-between gap-2",
+                        "Owner"
+                        input {
+                            class: "border px-1",
+                            value: text(&draft.read().repo_owner),
+                            oninput: move |evt| draft.write().repo_owner = non_empty(evt.value()),
+                        }
+                    }
+                    label { class: "flex flex-row justify-between gap-2",
+                        "Repo"
+                        input {
+                            class: "border px-1",
+                            value: text(&draft.read().repo_name),
+                            oninput: move |evt| draft.write().repo_name = non_empty(evt.value()),
+                        }
+                    }
+                    label { class: "flex flex-row justify-between gap-2",
+                        "Extensions"
+                        input {
+                            class: "border px-1",
+                            placeholder: "rs,toml",
+                            value: text(&draft.read().extensions),
+                            oninput: move |evt| draft.write().extensions = non_empty(evt.value()),
+                        }
+                    }
+                    label { class: "flex flex-row justify-between gap-2",
+                        "Additions"
+                        div {
+                            input {
+                                class: "border px-1 w-20",
+                                r#type: "number",
+                                min: "0",
+                                placeholder: "min",
+                                value: number(draft.read().min_additions),
+                                oninput: move |evt| draft.write().min_additions = evt.value().parse().ok(),
+                            }
+                            " - "
+                            input {
+                                class: "border px-1 w-20",
+                                r#type: "number",
+                                min: "0",
+                                placeholder: "max",
+                                value: number(draft.read().max_additions),
+                                oninput: move |evt| draft.write().max_additions = evt.value().parse().ok(),
+                            }
+                        }
+                    }
+                    label { class: "flex flex-row justify-between gap-2",
+                        "Deletions"
+                        div {
+                            input {
+                                class: "border px-1 w-20",
+                                r#type: "number",
+                                min: "0",
+                                placeholder: "min",
+                                value: number(draft.read().min_deletions),
+                                oninput: move |evt| draft.write().min_deletions = evt.value().parse().ok(),
+                            }
+                            " - "
+                            input {
+                                class: "border px-1 w-20",
+                                r#type: "number",
+                                min: "0",
+                                placeholder: "max",
+                                value: number(draft.read().max_deletions),
+                                oninput: move |evt| draft.write().max_deletions = evt.value().parse().ok(),
+                            }
+                        }
+                    }
+                    div { class: "flex flex-row justify-between",
+                        button {
+                            onclick: move |_| {
+                                open.set(false);
+                                on_apply.call(PrFilter::default());
+                            },
+                            "clear"
+                        }
+                        button {
+                            onclick: move |_| {
+                                open.set(false);
+                                on_apply.call(draft());
+                            },
+                            "apply"
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The trimmed text of an input, or none if it is blank
+fn non_empty(value: String) -> Option<String> {
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
 #[component]
 fn Card(class: String, data: PRData) -> Element {
     const VIDEO: Asset = asset!("/assets/minecraft.webm", AssetOptions::Unknown);
@@ -195,6 +591,9 @@ fn Card(class: String, data: PRData) -> Element {
     let title = data.pull_request_title;
     let user = data.user;
     let user_avatar = data.user_avatar;
+    let extended_until = data
+        .extended_until
+        .map(|deadline| deadline.format("%Y-%m-%d %H:%M UTC").to_string());
 
     rsx! {
         div { class: "w-[80vw] h-[80vh] {class}",
@@ -215,6 +614,12 @@ fn Card(class: String, data: PRData) -> Element {
                     }
                     div {
                         class: "flex flex-row items-center",
+                        if let Some(tally) = data.tally {
+                            span { class: "mx-4", "✅ {tally.accepted} ❌ {tally.denied} ⏭️ {tally.abstained}" }
+                        }
+                        if let Some(extended_until) = extended_until {
+                            span { class: "mx-4", "⏳ extended until {extended_until}" }
+                        }
                         "{user}"
                         img { class: "rounded-full w-[40px] h-[40px] mx-4",
                             src: "{user_avatar}"
@@ -264,12 +669,32 @@ fn Card(class: String, data: PRData) -> Element {
 
 #[derive(serde::Serialize, serde::Deserialize, PartialEq, Clone)]
 struct PRData {
-    source_url: String,
+    id: PrId,
     repo: String,
     pull_request_title: String,
     user: String,
     user_avatar: String,
     diff: GitDiff,
+    /// The votes so far. Unknown until someone votes while the card is shown
+    tally: Option<QueueTally>,
+    /// When the vote ends, if it was extended while the card is shown
+    extended_until: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl From<PullRequest> for PRData {
+    fn from(info: PullRequest) -> Self {
+        let diff = GitDiff::from_str(&info.diff).unwrap();
+        Self {
+            id: info.id(),
+            repo: info.repo_name,
+            pull_request_title: info.branch_to_merge,
+            user: info.author,
+            user_avatar: info.profile_pic_url,
+            diff,
+            tally: None,
+            extended_until: None,
+        }
+    }
 }
 
 #[derive(serde::Serialize, serde::Deserialize, PartialEq, Clone)]
@@ -0,0 +1,77 @@
+use dioxus::prelude::*;
+
+use crate::LeaderboardEntry;
+use crate::Leaderboards;
+use crate::Route;
+
+async fn get_leaderboards() -> Result<Leaderboards, String> {
+    let response = gloo_net::http::Request::get("/leaderboards")
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !response.ok() {
+        return Err(format!("the server answered {}", response.status()));
+    }
+    response.json().await.map_err(|e| e.to_string())
+}
+
+/// The best voters and authors
+#[component]
+pub fn Leaderboard() -> Element {
+    let leaderboards = use_resource(get_leaderboards);
+
+    rsx! {
+        div { class: "flex flex-col w-[100vw] min-h-[100vh] p-8 gap-4 font-mono",
+            div { class: "flex flex-row justify-between items-center",
+                h1 { class: "text-2xl font-bold", "🏆 Leaderboard" }
+                Link { to: Route::Home {}, "⬅️ back to voting" }
+            }
+            match &*leaderboards.read() {
+                None => rsx! { "loading..." },
+                Some(Err(error)) => rsx! { "Couldn't load the leaderboards: {error}" },
+                Some(Ok(leaderboards)) => rsx! {
+                    div { class: "grid grid-cols-1 md:grid-cols-2 gap-8",
+                        Board { title: "🗳️ Most votes cast", entries: leaderboards.votes_cast.clone() }
+                        Board { title: "🎯 Best agreement with the outcome", entries: leaderboards.agreement.clone() }
+                        Board { title: "🔥 Longest streaks", entries: leaderboards.streaks.clone() }
+                        Board { title: "✅ Most approved authors", entries: leaderboards.authors.clone() }
+                    }
+                },
+            }
+        }
+    }
+}
+
+#[component]
+fn Board(title: String, entries: Vec<LeaderboardEntry>) -> Element {
+    rsx! {
+        div {
+            h2 { class: "text-xl font-bold mb-2", "{title}" }
+            if entries.is_empty() {
+                "No one yet. Sign in and vote to get on the board!"
+            }
+            ol { class: "list-decimal list-inside",
+                for entry in entries {
+                    li { key: "{entry.name}",
+                        a { href: "https://github.com/{entry.name}", "{entry.name}" }
+                        " "
+                        {score(&entry)}
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The score of an entry, with the share it is for leaderboards that have one
+fn score(entry: &LeaderboardEntry) -> String {
+    match entry.out_of {
+        Some(out_of) if out_of > 0 => format!(
+            "{}/{} ({}%)",
+            entry.score,
+            out_of,
+            entry.score * 100 / out_of
+        ),
+        _ => entry.score.to_string(),
+    }
+}
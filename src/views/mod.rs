@@ -1,4 +1,12 @@
 #[cfg(not(feature = "server"))]
+mod history;
+#[cfg(not(feature = "server"))]
 mod home;
 #[cfg(not(feature = "server"))]
+mod leaderboard;
+#[cfg(not(feature = "server"))]
+pub use history::History;
+#[cfg(not(feature = "server"))]
 pub use home::Home;
+#[cfg(not(feature = "server"))]
+pub use leaderboard::Leaderboard;